@@ -0,0 +1,444 @@
+use reqwest::{header, Method, RequestBuilder, StatusCode};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::time::{self, Duration};
+
+use crate::{data::{DnsRecord, DnsUpdate, ExtendedResponse, RecordId, Response, ResultInfo, RetryPolicy, TokenStatus, Zone}, error::UpdateError};
+
+pub static ROOT : &str = "https://api.cloudflare.com/client/v4";
+
+/// A client for the DNS record, zone and token endpoints of the Cloudflare API, authenticated with an API token.
+#[derive(Clone)]
+pub struct CloudflareClient {
+    client : reqwest::Client,
+    base_url : String,
+    token : String,
+    retry : RetryPolicy
+}
+
+impl CloudflareClient {
+    pub fn new(client : reqwest::Client, token : impl Into<String>) -> Self {
+        Self { client, base_url: ROOT.to_owned(), token: token.into(), retry: RetryPolicy::default() }
+    }
+
+    /// Retries failed requests according to `retry`.
+    pub fn with_retry(mut self, retry : RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Sends requests to `base_url` instead of the public Cloudflare API.
+    pub fn with_base_url(mut self, base_url : impl Into<String>) -> Self {
+        self.base_url = base_url.into().trim_end_matches('/').to_owned();
+        self
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Lists the DNS records of a zone, following every page of the response. `query` is passed to Cloudflare
+    /// as a server-side filter, such as `[("name", "something.com"), ("type", "A")]`.
+    pub async fn list_dns_records(&self, zone_id : &str, query : &[(&str, &str)], per_page : u32) -> Result<ExtendedResponse<Vec<DnsRecord>>, UpdateError> {
+        let mut records = Vec::new();
+        let mut page = 1;
+
+        loop {
+            let req = self.request(Method::GET, &format!("/zones/{}/dns_records", zone_id))
+                .query(query)
+                .query(&[("page", page), ("per_page", per_page)]);
+
+            let mut v : ExtendedResponse<Vec<DnsRecord>> = self.send(req).await?;
+            let empty = v.result.is_empty();
+
+            records.append(&mut v.result);
+
+            let info = match v.result_info {
+                // Pages are counted locally, so that a server ignoring the page asked for cannot loop forever
+                Some(info) if page < info.total_pages && !empty => {
+                    page += 1;
+                    continue;
+                }
+                Some(info) => info,
+                // Responses without pagination metadata contain every record
+                None => ResultInfo { page, per_page, count: records.len() as u32, total_count: records.len() as u32, total_pages: page }
+            };
+
+            return Ok(ExtendedResponse {
+                response: v.response,
+                result_info: Some(ResultInfo { count: records.len() as u32, ..info }),
+                result: records
+            });
+        }
+    }
+
+    pub async fn get_dns_record(&self, zone_id : &str, id : &str) -> Result<ExtendedResponse<DnsRecord>, UpdateError> {
+        let req = self.request(Method::GET, &format!("/zones/{}/dns_records/{}", zone_id, id));
+
+        self.send(req).await
+    }
+
+    pub async fn create_dns_record(&self, zone_id : &str, record : &DnsUpdate) -> Result<ExtendedResponse<DnsRecord>, UpdateError> {
+        let req = self.request(Method::POST, &format!("/zones/{}/dns_records", zone_id))
+            .json(record);
+
+        self.send(req).await
+    }
+
+    /// Overwrites every field of an existing DNS record.
+    pub async fn update_dns_record(&self, zone_id : &str, id : &str, record : &DnsUpdate) -> Result<ExtendedResponse<DnsRecord>, UpdateError> {
+        let req = self.request(Method::PUT, &format!("/zones/{}/dns_records/{}", zone_id, id))
+            .json(record);
+
+        self.send(req).await
+    }
+
+    /// Changes only the fields present in `patch` of an existing DNS record.
+    pub async fn patch_dns_record<T : Serialize + ?Sized>(&self, zone_id : &str, id : &str, patch : &T) -> Result<ExtendedResponse<DnsRecord>, UpdateError> {
+        let req = self.request(Method::PATCH, &format!("/zones/{}/dns_records/{}", zone_id, id))
+            .json(patch);
+
+        self.send(req).await
+    }
+
+    pub async fn delete_dns_record(&self, zone_id : &str, id : &str) -> Result<ExtendedResponse<RecordId>, UpdateError> {
+        let req = self.request(Method::DELETE, &format!("/zones/{}/dns_records/{}", zone_id, id));
+
+        self.send(req).await
+    }
+
+    pub async fn get_zone(&self, zone_id : &str) -> Result<ExtendedResponse<Zone>, UpdateError> {
+        let req = self.request(Method::GET, &format!("/zones/{}", zone_id));
+
+        self.send(req).await
+    }
+
+    /// Checks that the API token is valid and active.
+    pub async fn verify_token(&self) -> Result<ExtendedResponse<TokenStatus>, UpdateError> {
+        let req = self.request(Method::GET, "/user/tokens/verify");
+
+        self.send(req).await
+    }
+
+    fn request(&self, method : Method, path : &str) -> RequestBuilder {
+        self.client.request(method, format!("{}{}", self.base_url, path))
+            .header(header::AUTHORIZATION, format!("Bearer {}", self.token))
+    }
+
+    /// Sends `req`, retrying transient failures with exponential backoff.
+    async fn send<T : DeserializeOwned>(&self, req : RequestBuilder) -> Result<ExtendedResponse<T>, UpdateError> {
+        let mut attempt = 1;
+
+        loop {
+            // Requests with streaming bodies cannot be cloned, so they are only sent once
+            let next = match req.try_clone() {
+                Some(v) if attempt < self.retry.max_attempts => v,
+                _ => return read_response(req.send().await?).await
+            };
+
+            let e = match next.send().await {
+                Ok(v) => match read_response(v).await {
+                    Ok(v) => return Ok(v),
+                    Err(e) => e
+                },
+                Err(e) => e.into()
+            };
+
+            if !e.is_transient() {
+                return Err(e);
+            }
+
+            let mut delay = backoff(&self.retry, attempt);
+
+            if let UpdateError::RateLimited { retry_after: Some(retry_after) } = e {
+                delay = delay.max(retry_after);
+            }
+
+            tracing::debug!("Cloudflare API request failed with error {}. Retrying in {:?} (attempt {} of {}).", e, delay, attempt, self.retry.max_attempts);
+
+            time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+/// The delay before retrying after `attempt` failed attempts.
+fn backoff(policy : &RetryPolicy, attempt : u32) -> Duration {
+    let delay = policy.base_delay
+        .saturating_mul(1u64.checked_shl(attempt - 1).unwrap_or(u64::MAX))
+        .min(policy.max_delay);
+
+    let delay = if policy.jitter {
+        // Equal jitter keeps at least half of the delay
+        delay / 2 + fastrand::u64(0..=delay / 2)
+    } else {
+        delay
+    };
+
+    Duration::from_millis(delay)
+}
+
+/// Decodes a Cloudflare API response, turning rate limiting, HTTP errors, undecodable bodies and
+/// unsuccessful responses into an [`UpdateError`].
+async fn read_response<T : DeserializeOwned>(v : reqwest::Response) -> Result<ExtendedResponse<T>, UpdateError> {
+    let status = v.status();
+
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        let retry_after = v.headers()
+            .get(header::RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .map(Duration::from_secs);
+
+        return Err(UpdateError::RateLimited { retry_after });
+    }
+
+    let body = v.text().await?;
+
+    let response : Response = match serde_json::from_str(&body) {
+        Ok(v) => v,
+        Err(_) if !status.is_success() => {
+            return Err(UpdateError::StatusError { status, body: excerpt(&body) });
+        }
+        Err(e) => {
+            return Err(UpdateError::DecodeError { source: e, body: excerpt(&body) });
+        }
+    };
+
+    if !response.success {
+        return Err(UpdateError::ApiError { status, errors: response.errors });
+    }
+
+    serde_json::from_str(&body).map_err(|e| UpdateError::DecodeError { source: e, body: excerpt(&body) })
+}
+
+fn excerpt(body : &str) -> String {
+    const MAX_LEN : usize = 256;
+
+    match body.char_indices().nth(MAX_LEN) {
+        Some((i, _)) => format!("{}...", &body[..i]),
+        None => body.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use serde_json::json;
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpListener};
+
+    use super::*;
+
+    /// Starts an HTTP server on the loopback interface which answers one request with each of `responses` in
+    /// turn. Returns its URL along with the request lines it received.
+    async fn server(responses : Vec<String>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let received = requests.clone();
+
+        tokio::spawn(async move {
+            for response in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut head = Vec::new();
+
+                // The requests of these tests have no body
+                while !head.ends_with(b"\r\n\r\n") {
+                    head.push(stream.read_u8().await.unwrap());
+                }
+
+                let head = String::from_utf8_lossy(&head).into_owned();
+                received.lock().unwrap().push(head.lines().next().unwrap_or_default().to_owned());
+
+                stream.write_all(response.as_bytes()).await.unwrap();
+                stream.shutdown().await.unwrap();
+            }
+        });
+
+        (url, requests)
+    }
+
+    fn response(status : &str, headers : &[(&str, &str)], content_type : &str, body : &str) -> String {
+        let headers : String = headers.iter().map(|(k, v)| format!("{}: {}\r\n", k, v)).collect();
+
+        format!("HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n{}\r\n{}", status, content_type, body.len(), headers, body)
+    }
+
+    fn json(body : serde_json::Value) -> String {
+        response("200 OK", &[], "application/json", &body.to_string())
+    }
+
+    fn record(id : &str, content : &str) -> serde_json::Value {
+        json!({ "id": id, "name": "a.example.com", "type": "A", "content": content, "ttl": 1, "proxied": false, "tags": [], "comment": null })
+    }
+
+    fn client(url : &str, max_attempts : u32) -> CloudflareClient {
+        let retry = RetryPolicy { max_attempts, base_delay: 1, max_delay: 1, jitter: false };
+
+        CloudflareClient::new(reqwest::Client::new(), "token").with_base_url(url).with_retry(retry)
+    }
+
+    #[tokio::test]
+    async fn lists_every_page() {
+        let info = |page| json!({ "page": page, "per_page": 2, "count": 2, "total_count": 3, "total_pages": 2 });
+
+        let (url, requests) = server(vec![
+            json(json!({ "success": true, "errors": [], "result": [record("r1", "192.0.2.1"), record("r2", "192.0.2.2")], "result_info": info(1) })),
+            json(json!({ "success": true, "errors": [], "result": [record("r3", "192.0.2.3")], "result_info": info(2) }))
+        ]).await;
+
+        let res = client(&url, 1).list_dns_records("z", &[("name", "a.example.com")], 2).await.unwrap();
+
+        assert_eq!(res.result.iter().map(|v| v.id.as_str()).collect::<Vec<_>>(), ["r1", "r2", "r3"]);
+        assert_eq!(res.result_info.unwrap().count, 3);
+        assert_eq!(*requests.lock().unwrap(), [
+            "GET /zones/z/dns_records?name=a.example.com&page=1&per_page=2 HTTP/1.1",
+            "GET /zones/z/dns_records?name=a.example.com&page=2&per_page=2 HTTP/1.1"
+        ]);
+    }
+
+    #[tokio::test]
+    async fn stops_when_pages_do_not_advance() {
+        // The server ignores the page asked for
+        let page = json!({ "success": true, "errors": [], "result": [record("r1", "192.0.2.1")], "result_info": { "page": 1, "per_page": 1, "count": 1, "total_count": 3, "total_pages": 3 } });
+        let (url, requests) = server(vec![json(page.clone()), json(page.clone()), json(page)]).await;
+
+        let res = client(&url, 1).list_dns_records("z", &[], 1).await.unwrap();
+
+        assert_eq!(res.result.len(), 3);
+        assert_eq!(*requests.lock().unwrap(), [
+            "GET /zones/z/dns_records?page=1&per_page=1 HTTP/1.1",
+            "GET /zones/z/dns_records?page=2&per_page=1 HTTP/1.1",
+            "GET /zones/z/dns_records?page=3&per_page=1 HTTP/1.1"
+        ]);
+    }
+
+    #[tokio::test]
+    async fn stops_at_empty_page() {
+        let info = |page| json!({ "page": page, "per_page": 1, "count": 1, "total_count": 5, "total_pages": 5 });
+
+        let (url, requests) = server(vec![
+            json(json!({ "success": true, "errors": [], "result": [record("r1", "192.0.2.1")], "result_info": info(1) })),
+            json(json!({ "success": true, "errors": [], "result": [], "result_info": info(2) }))
+        ]).await;
+
+        let res = client(&url, 1).list_dns_records("z", &[], 1).await.unwrap();
+
+        assert_eq!(res.result.len(), 1);
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn unsuccessful_response_is_api_error() {
+        let (url, _) = server(vec![
+            json(json!({ "success": false, "errors": [{ "code": 81044, "message": "Record does not exist." }], "result": null }))
+        ]).await;
+
+        match client(&url, 1).get_dns_record("z", "r1").await {
+            Err(UpdateError::ApiError { status, errors }) => {
+                assert_eq!(status, StatusCode::OK);
+                assert_eq!(errors[0].code, 81044);
+            }
+            v => panic!("unexpected result {:?}", v.map(|v| v.result))
+        }
+    }
+
+    #[tokio::test]
+    async fn html_error_page_is_status_error() {
+        let (url, _) = server(vec![
+            response("502 Bad Gateway", &[], "text/html", "<html><body>Bad gateway</body></html>")
+        ]).await;
+
+        match client(&url, 1).get_dns_record("z", "r1").await {
+            Err(UpdateError::StatusError { status, body }) => {
+                assert_eq!(status, StatusCode::BAD_GATEWAY);
+                assert!(body.contains("Bad gateway"));
+            }
+            v => panic!("unexpected result {:?}", v.map(|v| v.result))
+        }
+    }
+
+    #[tokio::test]
+    async fn undecodable_success_is_decode_error() {
+        let (url, _) = server(vec![response("200 OK", &[], "text/plain", "not json")]).await;
+
+        match client(&url, 1).get_dns_record("z", "r1").await {
+            Err(UpdateError::DecodeError { body, .. }) => assert_eq!(body, "not json"),
+            v => panic!("unexpected result {:?}", v.map(|v| v.result))
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_after_rate_limiting() {
+        let (url, requests) = server(vec![
+            response("429 Too Many Requests", &[("Retry-After", "1")], "application/json", "{}"),
+            json(json!({ "success": true, "errors": [], "result": record("r1", "192.0.2.1") }))
+        ]).await;
+
+        let start = time::Instant::now();
+        let res = client(&url, 2).get_dns_record("z", "r1").await.unwrap();
+
+        assert_eq!(res.result.id, "r1");
+        assert_eq!(requests.lock().unwrap().len(), 2);
+        assert!(start.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn retries_unsuccessful_server_errors() {
+        let error = json!({ "success": false, "errors": [{ "code": 10000, "message": "Internal error" }], "result": null });
+
+        let (url, requests) = server(vec![
+            response("503 Service Unavailable", &[], "application/json", &error.to_string()),
+            json(json!({ "success": true, "errors": [], "result": record("r1", "192.0.2.1") }))
+        ]).await;
+
+        let res = client(&url, 2).get_dns_record("z", "r1").await.unwrap();
+
+        assert_eq!(res.result.id, "r1");
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn single_attempt_is_not_retried() {
+        let (url, requests) = server(vec![
+            response("503 Service Unavailable", &[], "text/plain", "unavailable"),
+            json(json!({ "success": true, "errors": [], "result": record("r1", "192.0.2.1") }))
+        ]).await;
+
+        let res = client(&url, 1).get_dns_record("z", "r1").await;
+
+        assert!(matches!(res, Err(UpdateError::StatusError { status: StatusCode::SERVICE_UNAVAILABLE, .. })));
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max_delay() {
+        let policy = RetryPolicy { max_attempts: 5, base_delay: 500, max_delay: 3000, jitter: false };
+        let delays : Vec<u64> = (1..=5).map(|v| backoff(&policy, v).as_millis() as u64).collect();
+
+        assert_eq!(delays, [500, 1000, 2000, 3000, 3000]);
+    }
+
+    #[test]
+    fn backoff_does_not_overflow() {
+        let policy = RetryPolicy { max_attempts: u32::MAX, base_delay: 500, max_delay: 30000, jitter: false };
+
+        for attempt in [63, 64, 65, 100, u32::MAX] {
+            assert_eq!(backoff(&policy, attempt), Duration::from_millis(30000));
+        }
+    }
+
+    #[test]
+    fn jitter_keeps_half_of_the_delay() {
+        let policy = RetryPolicy { max_attempts: 5, base_delay: 500, max_delay: 30000, jitter: true };
+
+        for attempt in [1, 3, 64] {
+            let delay = backoff(&RetryPolicy { jitter: false, ..policy }, attempt);
+
+            for _ in 0..100 {
+                let jittered = backoff(&policy, attempt);
+                assert!(jittered >= delay / 2 && jittered <= delay, "{:?} is not within half of {:?}", jittered, delay);
+            }
+        }
+    }
+}
@@ -1,6 +1,8 @@
-use serde::{Deserialize, Serialize};
+use std::{fmt, net::{IpAddr, Ipv4Addr, Ipv6Addr}, path::PathBuf};
 
-use crate::error::CloudFlareError;
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::{error::{CloudFlareError, ConfigError}, record, secret};
 
 #[derive(Serialize, Deserialize)]
 pub struct Config {
@@ -8,11 +10,225 @@ pub struct Config {
     pub domains : Vec<DomainInfo>
 }
 
-#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+impl Config {
+    /// Checks every entry, as is done before a new config replaces the running one.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if let Some(quorum) = self.settings.ip_sources.invalid_quorum() {
+            return Err(ConfigError::InvalidQuorum { quorum, providers: self.settings.ip_sources.providers.len() });
+        }
+
+        for domain in self.domains.iter() {
+            for entry in domain.entries.iter() {
+                record::validate(entry).map_err(|e| ConfigError::InvalidEntry { zone_id: domain.zone_id.clone(), name: entry.name.clone(), source: e })?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Replaces every API key given as a reference to a secret with the secret itself.
+    pub fn resolve_secrets(&mut self) -> Result<(), ConfigError> {
+        for domain in self.domains.iter_mut() {
+            domain.api_key = secret::resolve(&domain.api_key).map_err(|e| ConfigError::SecretError {
+                zone_id: domain.zone_id.clone(),
+                reference: domain.api_key.clone(),
+                source: e
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct Settings {
     /// The interval of rechecking the public ip address in milliseconds
     pub ip_poll : u64,
-    pub update_upon_start : bool
+    pub update_upon_start : bool,
+    /// Whether the IP address is rechecked as soon as a network interface address changes (Linux only)
+    #[serde(default)]
+    pub watch_addresses : bool,
+    /// The number of DNS records requested per page when listing a zone
+    #[serde(default = "default_per_page")]
+    pub per_page : u32,
+    /// The base URL of the Cloudflare API
+    #[serde(default = "default_api_url")]
+    pub api_url : String,
+    /// How requests to the Cloudflare API are retried when they fail
+    #[serde(default)]
+    pub retry : RetryPolicy,
+    /// Where the last published content of every entry is kept across restarts, not kept when unset
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_file : Option<PathBuf>,
+    /// The interval of rereading the records in milliseconds to notice changes made outside of the
+    /// updater, not reread when unset
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub drift_check : Option<u64>,
+    /// Where the public IP addresses are obtained from
+    #[serde(default)]
+    pub ip_sources : IpSources
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RetryPolicy {
+    /// The maximum number of times a request is sent, including the first attempt
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts : u32,
+    /// The delay before the first retry in milliseconds, doubled after every attempt
+    #[serde(default = "default_base_delay")]
+    pub base_delay : u64,
+    /// The upper bound of the delay between attempts in milliseconds
+    #[serde(default = "default_max_delay")]
+    pub max_delay : u64,
+    /// Whether the delay is randomized to avoid retrying in lockstep
+    #[serde(default = "default_jitter")]
+    pub jitter : bool
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: default_max_attempts(),
+            base_delay: default_base_delay(),
+            max_delay: default_max_delay(),
+            jitter: default_jitter()
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct IpSources {
+    /// Providers queried in order until one of them returns an address. The `public_ip` backend is used when empty
+    #[serde(default)]
+    pub providers : Vec<IpSource>,
+    /// When set, every provider is queried concurrently and an address is only accepted when at least
+    /// this many providers agree on it
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quorum : Option<usize>
+}
+
+impl IpSources {
+    /// The quorum, when no address could ever be accepted with it
+    pub fn invalid_quorum(&self) -> Option<usize> {
+        self.quorum.filter(|v| *v == 0 || *v > self.providers.len())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum IpSource {
+    /// The resolvers built into the `public_ip` crate
+    PublicIp,
+    /// An HTTP endpoint returning the address of the caller
+    Http {
+        url : String,
+        #[serde(default)]
+        format : ResponseFormat,
+        /// JSON pointer to the address when `format` is `json`, such as `/ip`
+        #[serde(default)]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pointer : Option<String>,
+        /// Restricts the endpoint to one address family
+        #[serde(default)]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        family : Option<IpFamily>
+    },
+    /// A DNS server answering a query for `name` with the address of the caller
+    Dns {
+        name : String,
+        servers : Vec<IpAddr>,
+        #[serde(default = "default_dns_port")]
+        port : u16,
+        #[serde(default)]
+        query : DnsQuery
+    },
+    /// The addresses assigned to a local network interface
+    Interface {
+        interface : String,
+        #[serde(flatten)]
+        filter : AddressFilter
+    },
+    /// The external IPv4 address of the Internet Gateway Device found with UPnP
+    Upnp,
+    /// The external IPv4 address reported by a NAT-PMP gateway
+    NatPmp {
+        /// The gateway address, the default gateway when unset
+        #[serde(default)]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        gateway : Option<Ipv4Addr>
+    },
+    /// The external address reported by a PCP server
+    Pcp {
+        /// The PCP server address, the default gateway when unset
+        #[serde(default)]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        gateway : Option<IpAddr>
+    },
+    /// STUN servers queried in order, each given as `host:port`
+    Stun {
+        servers : Vec<String>
+    },
+    /// A program printing the address on its standard output
+    Command {
+        command : String,
+        #[serde(default)]
+        args : Vec<String>,
+        /// The time the program may run for in milliseconds
+        #[serde(default = "default_command_timeout")]
+        timeout : u64,
+        /// Restricts the program to one address family
+        #[serde(default)]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        family : Option<IpFamily>
+    }
+}
+
+/// Kinds of addresses skipped when reading the addresses of a network interface
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AddressFilter {
+    /// IPv6 privacy extension addresses
+    #[serde(default = "default_true")]
+    pub exclude_temporary : bool,
+    /// Addresses past their preferred lifetime
+    #[serde(default = "default_true")]
+    pub exclude_deprecated : bool,
+    /// `169.254.0.0/16` and `fe80::/10`
+    #[serde(default = "default_true")]
+    pub exclude_link_local : bool,
+    /// Unique local IPv6 addresses (`fc00::/7`)
+    #[serde(default = "default_true")]
+    pub exclude_ula : bool,
+    /// Private and shared IPv4 ranges, along with unique local IPv6 addresses
+    #[serde(default = "default_true")]
+    pub exclude_private : bool
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseFormat {
+    #[default]
+    Text,
+    Json
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum IpFamily {
+    Ipv4,
+    Ipv6
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DnsQuery {
+    /// `A` records for IPv4 and `AAAA` records for IPv6
+    #[default]
+    Address,
+    /// A `TXT` record containing the address
+    Txt
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -22,7 +238,7 @@ pub struct DomainInfo {
     pub entries : Vec<Entry>
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub struct Entry {
     pub name : String,
     #[serde(default = "default_ttl")]
@@ -32,20 +248,172 @@ pub struct Entry {
     pub proxied : Option<bool>,
     #[serde(default = "default_type")]
     #[serde(rename = "type")]
-    pub record_type : String,
+    pub record_type : RecordType,
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tags : Option<Vec<String>>,
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub comment : Option<String>
+    pub comment : Option<String>,
+    /// Whether the record should be created when it does not exist in the zone
+    #[serde(default)]
+    #[serde(skip_serializing)]
+    pub create_if_missing : bool,
+    /// Where the IP address of this entry is obtained from, instead of the sources in the settings
+    #[serde(default)]
+    #[serde(skip_serializing)]
+    pub ip_sources : Option<IpSources>,
+    /// Interface identifier combined with the detected IPv6 prefix, for AAAA records of other hosts
+    #[serde(default)]
+    #[serde(skip_serializing)]
+    pub ipv6_suffix : Option<Ipv6Addr>,
+    /// The length of the detected IPv6 prefix kept when `ipv6_suffix` is set
+    #[serde(default = "default_prefix_length")]
+    #[serde(skip_serializing)]
+    pub prefix_length : u8,
+    /// Content template of the record, with `{ipv4}` and `{ipv6}` replaced by the detected addresses.
+    /// The detected address of the record type is used when unset
+    #[serde(default)]
+    #[serde(skip_serializing)]
+    pub content : Option<String>,
+    /// Priority of HTTPS, SVCB, URI and SRV records
+    #[serde(default)]
+    #[serde(skip_serializing)]
+    pub priority : Option<u16>,
+    /// Weight of URI and SRV records
+    #[serde(default)]
+    #[serde(skip_serializing)]
+    pub weight : Option<u16>,
+    /// Port of SRV records
+    #[serde(default)]
+    #[serde(skip_serializing)]
+    pub port : Option<u16>,
+    /// Target of HTTPS, SVCB, URI and SRV records
+    #[serde(default)]
+    #[serde(skip_serializing)]
+    pub target : Option<String>,
+    /// Service parameters of HTTPS and SVCB records, such as `alpn="h2,h3"`. The `ipv4hint` and
+    /// `ipv6hint` parameters are filled from the detected addresses unless given
+    #[serde(default)]
+    #[serde(skip_serializing)]
+    pub svc_params : Option<String>,
+    /// Whether the entry updates a single record, or owns some of the records sharing its name and type
+    #[serde(default)]
+    #[serde(skip_serializing)]
+    pub mode : EntryMode,
+    /// Identifies the records owned by this entry in `set` mode, such as the host name of the machine
+    #[serde(default)]
+    #[serde(skip_serializing)]
+    pub owner : Option<String>,
+    /// How the records owned by this entry are marked in `set` mode
+    #[serde(default)]
+    #[serde(skip_serializing)]
+    pub owner_marker : OwnerMarker,
+    /// What is done when the record was changed outside of the updater
+    #[serde(default)]
+    #[serde(skip_serializing)]
+    pub drift : DriftPolicy
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryMode {
+    /// Updates the only record with the name and type of the entry
+    #[default]
+    Single,
+    /// Keeps exactly one record with the desired content among the records marked with the owner of
+    /// the entry, leaving records of other owners untouched
+    Set
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OwnerMarker {
+    /// A `cloudflareddns:<owner>` tag
+    #[default]
+    Tag,
+    /// A `cloudflareddns:<owner>` comment, replacing the comment of the entry
+    Comment
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DriftPolicy {
+    /// Restores the content published by the updater
+    #[default]
+    Correct,
+    /// Logs a warning and leaves the record as is until the IP address changes
+    Warn,
+    /// Takes the changed record as the published one, saving it to the state file, and leaves it as is until the
+    /// IP address changes
+    Adopt
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum RecordType {
+    A,
+    Aaaa,
+    Txt,
+    Cname,
+    Https,
+    Svcb,
+    Uri,
+    Srv
+}
+
+impl RecordType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::A => "A",
+            Self::Aaaa => "AAAA",
+            Self::Txt => "TXT",
+            Self::Cname => "CNAME",
+            Self::Https => "HTTPS",
+            Self::Svcb => "SVCB",
+            Self::Uri => "URI",
+            Self::Srv => "SRV"
+        }
+    }
+}
+
+impl fmt::Display for RecordType {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Clone)]
 pub struct DnsUpdate {
-    #[serde(flatten)]
     pub entry : Entry,
-    pub content : String
+    pub content : String,
+    /// Structured content of HTTPS, SVCB, URI and SRV records, sent instead of `content`
+    pub data : Option<serde_json::Value>,
+    /// Priority of URI records, which is not part of `data`
+    pub priority : Option<u16>
+}
+
+impl Serialize for DnsUpdate {
+    fn serialize<S : Serializer>(&self, serializer : S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Body<'a> {
+            #[serde(flatten)]
+            entry : &'a Entry,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            content : Option<&'a str>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            data : Option<&'a serde_json::Value>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            priority : Option<u16>
+        }
+
+        Body {
+            entry: &self.entry,
+            content: if self.data.is_none() { Some(&self.content) } else { None },
+            data: self.data.as_ref(),
+            priority: self.priority
+        }.serialize(serializer)
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -57,25 +425,99 @@ pub struct Response {
 pub struct ExtendedResponse<T> {
     #[serde(flatten)]
     pub response : Response,
-    pub result : T
+    pub result : T,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result_info : Option<ResultInfo>
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub struct ResultInfo {
+    pub page : u32,
+    pub per_page : u32,
+    pub count : u32,
+    pub total_count : u32,
+    pub total_pages : u32
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct DnsRecord {
     pub content : String,
     pub id : String,
     pub name : String,
     #[serde(rename = "type")]
     pub record_type : String,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttl : Option<usize>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxied : Option<bool>,
+    #[serde(default)]
+    pub tags : Vec<String>,
+    #[serde(default)]
+    pub comment : Option<String>,
+    /// The structured content of HTTPS, SVCB, URI and SRV records
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data : Option<serde_json::Value>
 }
 
-pub fn default_type() -> String {
-    "A".to_owned()
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Zone {
+    pub id : String,
+    pub name : String
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TokenStatus {
+    pub id : String,
+    /// `active` for tokens which can be used
+    pub status : String
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RecordId {
+    pub id : String
+}
+
+pub fn default_type() -> RecordType {
+    RecordType::A
 }
 
 pub fn default_ttl() -> usize {
     1
 }
+pub fn default_prefix_length() -> u8 {
+    64
+}
+pub fn default_per_page() -> u32 {
+    100
+}
+pub fn default_api_url() -> String {
+    crate::client::ROOT.to_owned()
+}
+pub fn default_max_attempts() -> u32 {
+    5
+}
+pub fn default_base_delay() -> u64 {
+    500
+}
+pub fn default_max_delay() -> u64 {
+    30000
+}
+pub fn default_jitter() -> bool {
+    true
+}
+pub fn default_true() -> bool {
+    true
+}
+pub fn default_command_timeout() -> u64 {
+    10000
+}
+pub fn default_dns_port() -> u16 {
+    53
+}
 pub fn default_tags() -> Vec<String> {
     Vec::new()
 }
@@ -0,0 +1,303 @@
+use serde_json::Value;
+
+use crate::{data::{DnsRecord, DnsUpdate, DriftPolicy, Entry}, record, state::EntryState};
+
+/// What the task of an entry starts from
+pub struct Seed {
+    /// The update the entry is known to have published, which is published again once the desired update differs
+    pub update : DnsUpdate,
+    /// The changed record already reported
+    pub reported : Option<DnsRecord>,
+    /// Whether the live record was adopted in place of `update`, which is then saved to the state file
+    pub adopted : bool
+}
+
+/// What is done about a record changed outside of the updater
+#[derive(Debug, PartialEq, Eq)]
+pub enum Drift {
+    /// The record still has what was published
+    Unchanged,
+    /// The change was already reported and is left as is
+    Reported,
+    /// The fields other than the content changed and are patched back
+    RestoreFields,
+    /// The content changed and is published again
+    Restore,
+    /// The change is left as is until the IP address changes
+    Warn,
+    /// The change is taken as the published record, which is saved to the state file
+    Adopt
+}
+
+/// Picks what `entry` is known to have published at startup from its live `record` and the state `known` from
+/// before the restart, handling changes made while the updater was not running.
+pub fn seed(entry : &Entry, record : Option<&DnsRecord>, known : Option<EntryState>) -> Seed {
+    let seeded = |content : String, data : Option<Value>| DnsUpdate { entry: entry.clone(), content, data, priority: None };
+    let seed = |update, reported, adopted| Seed { update, reported, adopted };
+
+    // Content published before a restart only applies to the same record
+    let known = known.filter(|v| v.id.as_deref() == record.map(|v| v.id.as_str()));
+
+    let (record, published, desired) = match (record, known) {
+        // Record sets are only known by what was last published
+        (None, known) => return seed(known.map(|v| seeded(v.content, v.data)).unwrap_or_else(|| seeded(String::new(), None)), None, false),
+        (Some(record), None) => return seed(seeded(record.content.clone(), record.data.clone()), None, false),
+        (Some(record), Some(known)) => (record, seeded(known.content, known.data), known.desired)
+    };
+
+    // The update an adopted change replaced is only published again once the addresses change
+    let desired = desired.filter(|_| entry.drift == DriftPolicy::Adopt).map(|v| seeded(v, None));
+
+    if record::is_current(record, &published) {
+        return match desired {
+            Some(desired) => seed(desired, Some(record.clone()), false),
+            None => seed(published, None, false)
+        };
+    }
+
+    match entry.drift {
+        DriftPolicy::Correct => {
+            tracing::warn!("Entry {} was changed from {} to {} while the updater was not running. Restoring it on the next poll.", entry.name, published.content, record.content);
+            seed(seeded(record.content.clone(), record.data.clone()), None, false)
+        }
+        DriftPolicy::Warn => {
+            tracing::warn!("Entry {} was changed from {} to {} while the updater was not running. Leaving it as is until the IP address changes.", entry.name, published.content, record.content);
+            seed(published, Some(record.clone()), false)
+        }
+        DriftPolicy::Adopt => {
+            tracing::info!("Entry {} was changed from {} to {} while the updater was not running. Adopting the change until the IP address changes.", entry.name, published.content, record.content);
+            seed(desired.unwrap_or(published), Some(record.clone()), true)
+        }
+    }
+}
+
+/// Decides what is done about the live `record` of an entry which last published `update`, according to the
+/// drift policy of the entry. `reported` is the changed record already reported.
+pub fn check(record : &DnsRecord, update : &DnsUpdate, reported : Option<&DnsRecord>) -> Drift {
+    let entry = &update.entry;
+    let current = record::is_current(record, update);
+    let fields = record::changed_fields(record, update);
+
+    if current && fields.is_empty() {
+        return Drift::Unchanged;
+    }
+
+    if reported == Some(record) {
+        return Drift::Reported;
+    }
+
+    let mut changes : Vec<String> = fields.keys().cloned().collect();
+
+    if !current {
+        changes.insert(0, format!("content to {}", record.content));
+    }
+
+    let changes = changes.join(", ");
+
+    match entry.drift {
+        DriftPolicy::Correct if current => {
+            tracing::warn!("Entry {} was changed outside of the updater ({}). Restoring it.", entry.name, changes);
+            Drift::RestoreFields
+        }
+        DriftPolicy::Correct => {
+            tracing::warn!("Entry {} was changed outside of the updater ({}). Restoring {}.", entry.name, changes, update.content);
+            Drift::Restore
+        }
+        DriftPolicy::Warn => {
+            tracing::warn!("Entry {} was changed outside of the updater ({}). Leaving it as is until the IP address changes.", entry.name, changes);
+            Drift::Warn
+        }
+        DriftPolicy::Adopt => {
+            tracing::info!("Entry {} was changed outside of the updater ({}). Adopting the change until the IP address changes.", entry.name, changes);
+            Drift::Adopt
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use crate::data::RecordType;
+
+    use super::*;
+
+    fn entry(drift : &str) -> Entry {
+        toml::from_str(&format!("name = \"a.example.com\"\ndrift = \"{}\"", drift)).unwrap()
+    }
+
+    fn record(entry : &Entry, content : &str) -> DnsRecord {
+        DnsRecord {
+            content: content.to_owned(),
+            id: "r1".to_owned(),
+            name: entry.name.clone(),
+            record_type: "A".to_owned(),
+            ttl: Some(entry.ttl),
+            proxied: Some(false),
+            tags: Vec::new(),
+            comment: None,
+            data: None
+        }
+    }
+
+    fn state(content : &str, desired : Option<&str>) -> EntryState {
+        EntryState {
+            zone_id: "z".to_owned(),
+            name: "a.example.com".to_owned(),
+            record_type: RecordType::A,
+            id: Some("r1".to_owned()),
+            content: content.to_owned(),
+            data: None,
+            desired: desired.map(str::to_owned),
+            published_at: 0
+        }
+    }
+
+    /// The update published for the address `ipv4`
+    fn published(entry : &Entry, ipv4 : &str) -> DnsUpdate {
+        record::desired_update(entry, &(Some(ipv4.parse::<Ipv4Addr>().unwrap()), None)).unwrap()
+    }
+
+    /// Whether a poll detecting `ipv4` publishes the entry, as decided by its task
+    fn publishes(seed : &Seed, ipv4 : &str) -> bool {
+        !record::same_content(&seed.update, &published(&seed.update.entry, ipv4))
+    }
+
+    #[test]
+    fn unchanged_records_are_left_alone() {
+        for policy in ["correct", "warn", "adopt"] {
+            let entry = entry(policy);
+            let update = published(&entry, "203.0.113.1");
+
+            assert_eq!(check(&record(&entry, "203.0.113.1"), &update, None), Drift::Unchanged);
+        }
+    }
+
+    #[test]
+    fn correct_restores_changes() {
+        let entry = entry("correct");
+        let update = published(&entry, "203.0.113.1");
+
+        assert_eq!(check(&record(&entry, "198.51.100.1"), &update, None), Drift::Restore);
+
+        let mut ttl = record(&entry, "203.0.113.1");
+        ttl.ttl = Some(60);
+        assert_eq!(check(&ttl, &update, None), Drift::RestoreFields);
+    }
+
+    #[test]
+    fn warn_reports_changes_once() {
+        let entry = entry("warn");
+        let update = published(&entry, "203.0.113.1");
+        let changed = record(&entry, "198.51.100.1");
+
+        assert_eq!(check(&changed, &update, None), Drift::Warn);
+        assert_eq!(check(&changed, &update, Some(&changed)), Drift::Reported);
+        assert_eq!(check(&record(&entry, "198.51.100.2"), &update, Some(&changed)), Drift::Warn);
+    }
+
+    #[test]
+    fn adopt_adopts_changes() {
+        let entry = entry("adopt");
+        let update = published(&entry, "203.0.113.1");
+        let changed = record(&entry, "198.51.100.1");
+
+        assert_eq!(check(&changed, &update, None), Drift::Adopt);
+        assert_eq!(check(&changed, &update, Some(&changed)), Drift::Reported);
+    }
+
+    #[test]
+    fn seeds_from_live_record_without_state() {
+        let entry = entry("correct");
+        let seed = seed(&entry, Some(&record(&entry, "198.51.100.1")), None);
+
+        assert_eq!(seed.update.content, "198.51.100.1");
+        assert!(seed.reported.is_none() && !seed.adopted);
+        assert!(publishes(&seed, "203.0.113.1"));
+        assert!(!publishes(&seed, "198.51.100.1"));
+    }
+
+    #[test]
+    fn ignores_state_of_other_records() {
+        let entry = entry("warn");
+        let mut known = state("203.0.113.1", None);
+        known.id = Some("r2".to_owned());
+
+        let seed = seed(&entry, Some(&record(&entry, "198.51.100.1")), Some(known));
+
+        assert_eq!(seed.update.content, "198.51.100.1");
+        assert!(seed.reported.is_none());
+    }
+
+    #[test]
+    fn seeds_record_sets_from_state() {
+        let entry = entry("correct");
+        let mut known = state("203.0.113.1", None);
+        known.id = None;
+
+        assert_eq!(seed(&entry, None, Some(known)).update.content, "203.0.113.1");
+        assert_eq!(seed(&entry, None, None).update.content, "");
+    }
+
+    #[test]
+    fn restart_without_changes_does_not_republish() {
+        for policy in ["correct", "warn", "adopt"] {
+            let entry = entry(policy);
+            let seed = seed(&entry, Some(&record(&entry, "203.0.113.1")), Some(state("203.0.113.1", None)));
+
+            assert!(seed.reported.is_none() && !seed.adopted);
+            assert!(!publishes(&seed, "203.0.113.1"));
+        }
+    }
+
+    #[test]
+    fn correct_restores_changes_after_restart() {
+        let entry = entry("correct");
+        let seed = seed(&entry, Some(&record(&entry, "198.51.100.1")), Some(state("203.0.113.1", None)));
+
+        assert!(seed.reported.is_none() && !seed.adopted);
+        assert!(publishes(&seed, "203.0.113.1"));
+    }
+
+    #[test]
+    fn warn_keeps_changes_after_restart() {
+        let entry = entry("warn");
+        let changed = record(&entry, "198.51.100.1");
+        let seed = seed(&entry, Some(&changed), Some(state("203.0.113.1", None)));
+
+        assert_eq!(seed.reported.as_ref(), Some(&changed));
+        assert!(!seed.adopted);
+        assert!(!publishes(&seed, "203.0.113.1"));
+        assert!(publishes(&seed, "203.0.113.2"));
+    }
+
+    #[test]
+    fn adopted_changes_last_until_the_address_changes() {
+        let entry = entry("adopt");
+        let changed = record(&entry, "198.51.100.1");
+
+        // Changed while the updater was not running
+        let first = seed(&entry, Some(&changed), Some(state("203.0.113.1", None)));
+
+        assert!(first.adopted);
+        assert_eq!(first.reported.as_ref(), Some(&changed));
+        assert!(!publishes(&first, "203.0.113.1"));
+
+        // Restarted with the adopted record saved in place of the published one
+        let second = seed(&entry, Some(&changed), Some(state("198.51.100.1", Some(&first.update.content))));
+
+        assert!(!second.adopted);
+        assert_eq!(check(&changed, &second.update, second.reported.as_ref()), Drift::Reported);
+        assert!(!publishes(&second, "203.0.113.1"));
+        assert!(publishes(&second, "203.0.113.2"));
+    }
+
+    #[test]
+    fn adopted_state_is_ignored_by_other_policies() {
+        let entry = entry("correct");
+        let seed = seed(&entry, Some(&record(&entry, "198.51.100.1")), Some(state("198.51.100.1", Some("203.0.113.1"))));
+
+        assert!(seed.reported.is_none());
+        assert!(publishes(&seed, "203.0.113.1"));
+    }
+}
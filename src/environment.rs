@@ -0,0 +1,202 @@
+use std::env;
+
+use toml::{value::Table, Value};
+
+use crate::error::ConfigError;
+
+/// The prefix of the environment variables overriding the config
+pub const PREFIX : &str = "CFDDNS_";
+
+/// The environment variables overriding the config, sorted by name. Variables which are not valid
+/// Unicode are ignored.
+pub fn vars() -> Vec<(String, String)> {
+    let mut vars : Vec<(String, String)> = env::vars_os()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+        .filter(|(name, _)| name.starts_with(PREFIX))
+        .collect();
+
+    vars.sort();
+    vars
+}
+
+/// Sets the values of `vars` in `config`. The name of every variable is a path into the config with its
+/// segments separated by `__`, such as `CFDDNS_SETTINGS__IP_POLL` or `CFDDNS_DOMAINS__0__ENTRIES__0__NAME`,
+/// where numbers index arrays. Values are read as TOML integers, booleans, strings, arrays or inline tables, or
+/// else as plain strings, so numeric strings must be quoted.
+pub fn overlay(config : &mut Value, vars : &[(String, String)]) -> Result<(), ConfigError> {
+    for (name, value) in vars {
+        let path : Vec<String> = name[PREFIX.len()..].split("__").map(|v| v.to_lowercase()).collect();
+
+        if path.iter().any(|v| v.is_empty()) || !set(config, &path, parse_value(value)) {
+            return Err(ConfigError::InvalidVariable(name.clone()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Sets the value at `path` in `root`, creating the tables and arrays on the way. Returns false when the
+/// path goes through a value which is not a table or array, or indexes an array with something else
+/// than a number.
+fn set(root : &mut Value, path : &[String], value : Value) -> bool {
+    let (key, rest) = match path.split_first() {
+        Some(v) => v,
+        None => return false
+    };
+
+    let child = match root {
+        Value::Table(table) => table.entry(key.clone()).or_insert_with(|| empty_for(rest)),
+        Value::Array(array) => {
+            let index : usize = match key.parse() {
+                Ok(v) => v,
+                Err(_) => return false
+            };
+
+            while array.len() <= index {
+                array.push(empty_for(rest));
+            }
+
+            &mut array[index]
+        }
+        _ => return false
+    };
+
+    if rest.is_empty() {
+        *child = value;
+        return true;
+    }
+
+    set(child, rest, value)
+}
+
+/// An empty array when the next segment of the path is an index, or else an empty table.
+fn empty_for(rest : &[String]) -> Value {
+    match rest.first() {
+        Some(v) if v.parse::<usize>().is_ok() => Value::Array(Vec::new()),
+        _ => Value::Table(Table::new())
+    }
+}
+
+fn parse_value(text : &str) -> Value {
+    // Values spanning several lines could define other keys than the value
+    if !text.contains('\n') {
+        if let Ok(mut table) = toml::from_str::<Table>(&format!("value = {}", text)) {
+            match table.remove("value") {
+                // No setting is a float or date, while names such as `inf` or `1.2` are plain strings
+                Some(Value::Float(_) | Value::Datetime(_)) | None => {}
+                Some(v) => return v
+            }
+        }
+    }
+
+    Value::String(text.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::Config;
+
+    use super::*;
+
+    /// Overlays the variables named `CFDDNS_<name>` on the config file `file`.
+    fn overlay_on(file : &str, vars : &[(&str, &str)]) -> Result<Value, ConfigError> {
+        let mut config : Value = toml::from_str(file).unwrap();
+        let vars : Vec<(String, String)> = vars.iter().map(|(k, v)| (format!("{}{}", PREFIX, k), v.to_string())).collect();
+
+        overlay(&mut config, &vars).map(|_| config)
+    }
+
+    #[test]
+    fn creates_nested_arrays() {
+        let config = overlay_on("", &[
+            ("DOMAINS__0__ZONE_ID", "z"),
+            ("DOMAINS__0__ENTRIES__0__NAME", "a.example.com"),
+            ("DOMAINS__0__ENTRIES__1__NAME", "b.example.com"),
+            ("DOMAINS__0__ENTRIES__1__TAGS__0", "home")
+        ]).unwrap();
+
+        let expected : Value = toml::from_str(r#"
+            [[domains]]
+            zone_id = "z"
+            entries = [{ name = "a.example.com" }, { name = "b.example.com", tags = ["home"] }]
+        "#).unwrap();
+
+        assert_eq!(config, expected);
+    }
+
+    #[test]
+    fn pads_gaps_in_arrays() {
+        let config = overlay_on("", &[("DOMAINS__1__ZONE_ID", "z")]).unwrap();
+
+        // The empty domain is then rejected by the config for its missing fields
+        assert_eq!(config["domains"].as_array().unwrap(), &[Value::Table(Table::new()), toml::from_str::<Value>(r#"zone_id = "z""#).unwrap()]);
+    }
+
+    #[test]
+    fn overrides_file_values() {
+        let file = r#"
+            [settings]
+            ip_poll = 10000
+            update_upon_start = false
+
+            [[domains]]
+            zone_id = "z"
+            api_key = "file:/run/token"
+            entries = [{ name = "a.example.com" }, { name = "b.example.com" }]
+        "#;
+
+        let config = overlay_on(file, &[("SETTINGS__IP_POLL", "20000"), ("DOMAINS__0__ENTRIES__1__NAME", "c.example.com")]).unwrap();
+
+        assert_eq!(config["settings"]["ip_poll"], Value::Integer(20000));
+        assert_eq!(config["settings"]["update_upon_start"], Value::Boolean(false));
+        assert_eq!(config["domains"][0]["api_key"].as_str(), Some("file:/run/token"));
+        assert_eq!(config["domains"][0]["entries"][0]["name"].as_str(), Some("a.example.com"));
+        assert_eq!(config["domains"][0]["entries"][1]["name"].as_str(), Some("c.example.com"));
+    }
+
+    #[test]
+    fn rejects_invalid_paths() {
+        let file = "[settings]\nip_poll = 10000\n[[domains]]\nzone_id = \"z\"";
+
+        for name in ["SETTINGS____IP_POLL", "SETTINGS__", "SETTINGS__IP_POLL__SECONDS", "DOMAINS__FIRST__ZONE_ID"] {
+            match overlay_on(file, &[(name, "1")]) {
+                Err(ConfigError::InvalidVariable(v)) => assert_eq!(v, format!("{}{}", PREFIX, name)),
+                v => panic!("{} was not rejected: {:?}", name, v)
+            }
+        }
+    }
+
+    #[test]
+    fn reads_values_as_toml() {
+        assert_eq!(parse_value("20000"), Value::Integer(20000));
+        assert_eq!(parse_value("true"), Value::Boolean(true));
+        assert_eq!(parse_value(r#"["a", "b"]"#), Value::Array(vec![Value::String("a".to_owned()), Value::String("b".to_owned())]));
+        assert_eq!(parse_value("env:CF_TOKEN"), Value::String("env:CF_TOKEN".to_owned()));
+        assert_eq!(parse_value("a\nb = 1"), Value::String("a\nb = 1".to_owned()));
+
+        // Floats and dates are never settings
+        assert_eq!(parse_value("inf"), Value::String("inf".to_owned()));
+        assert_eq!(parse_value("1.5"), Value::String("1.5".to_owned()));
+        assert_eq!(parse_value("2024-01-01"), Value::String("2024-01-01".to_owned()));
+    }
+
+    #[test]
+    fn numeric_strings_must_be_quoted() {
+        let vars = |comment| [
+            ("SETTINGS__IP_POLL", "20000"),
+            ("SETTINGS__UPDATE_UPON_START", "true"),
+            ("DOMAINS__0__ZONE_ID", "z"),
+            ("DOMAINS__0__API_KEY", "token"),
+            ("DOMAINS__0__ENTRIES__0__NAME", "inf"),
+            ("DOMAINS__0__ENTRIES__0__COMMENT", comment)
+        ];
+
+        assert!(overlay_on("", &vars("2024")).unwrap().try_into::<Config>().is_err());
+
+        let config : Config = overlay_on("", &vars(r#""2024""#)).unwrap().try_into().unwrap();
+        let entry = &config.domains[0].entries[0];
+
+        assert_eq!(entry.name, "inf");
+        assert_eq!(entry.comment.as_deref(), Some("2024"));
+    }
+}
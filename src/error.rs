@@ -1,24 +1,104 @@
-use std::io::Error as IoError;
+use std::{io::Error as IoError, process::ExitStatus, time::Duration};
+use reqwest::StatusCode;
 use serde::{Serialize, Deserialize};
+use serde_json::Error as JsonError;
 use toml::de::Error as TomlError;
 
 use thiserror::Error;
 
+use crate::data::RecordType;
+
 #[derive(Error, Debug)]
 #[error(transparent)]
 pub enum ConfigError {
-    #[error("Config.toml file not found or cannot be read")]
+    #[error("config file not found or cannot be read: {0}")]
     FileError(#[from]IoError),
-    #[error("Config.toml file is in an invalid format")]
-    ParsingError(#[from]TomlError)
+    #[error("config file is in an invalid format: {0}")]
+    ParsingError(#[from]TomlError),
+    #[error("entry {name} of zone {zone_id} is improper: {source}")]
+    InvalidEntry { zone_id : String, name : String, source : EntryError },
+    #[error("environment variable {0} does not match the structure of the config")]
+    InvalidVariable(String),
+    #[error("cannot resolve the API key {reference} of zone {zone_id}: {source}")]
+    SecretError { zone_id : String, reference : String, source : SecretError },
+    #[error("quorum {quorum} of the IP address providers must be between 1 and the number of providers ({providers})")]
+    InvalidQuorum { quorum : usize, providers : usize }
+}
+
+#[derive(Error, Debug)]
+pub enum SecretError {
+    #[error("environment variable {0} is not set")]
+    MissingVariable(String),
+    #[error("cannot read the secret file: {0}")]
+    FileError(#[from]IoError),
+    #[error("$CREDENTIALS_DIRECTORY is not set, credentials are only available to systemd services")]
+    NoCredentialsDirectory,
+    #[error("secret is empty")]
+    Empty
 }
 
 
 
 #[derive(Error, Debug)]
 pub enum UpdateError {
-    #[error("cannot put ip address changes")]
+    #[error("cannot reach the Cloudflare API: {0}")]
     ReqError(#[from]reqwest::Error),
+    #[error("Cloudflare API responded with status {status}: {body}")]
+    StatusError { status : StatusCode, body : String },
+    #[error("rate limited by the Cloudflare API (retry after {retry_after:?})")]
+    RateLimited { retry_after : Option<Duration> },
+    #[error("cannot decode Cloudflare API response ({source}): {body}")]
+    DecodeError { source : JsonError, body : String },
+    #[error("erroneous message received from Cloudflare API with status {status}: {}", join_errors(.errors))]
+    ApiError { status : StatusCode, errors : Vec<CloudFlareError> }
+}
+
+impl UpdateError {
+    /// Whether the request may succeed if it is sent again later
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::ReqError(_) | Self::RateLimited { .. } | Self::DecodeError { .. } => true,
+            Self::StatusError { status, .. } | Self::ApiError { status, .. } => status.is_server_error()
+        }
+    }
+}
+
+fn join_errors(errors : &[CloudFlareError]) -> String {
+    if errors.is_empty() {
+        return "no error given".to_owned();
+    }
+
+    errors.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+#[derive(Error, Debug)]
+pub enum StateError {
+    #[error("cannot read or write the state file: {0}")]
+    FileError(#[from]IoError),
+    #[error("state file is in an invalid format: {0}")]
+    ParsingError(#[from]JsonError)
+}
+
+#[derive(Error, Debug)]
+pub enum EntryError {
+    #[error("{0} entries require {1} to be set")]
+    MissingField(RecordType, &'static str),
+    #[error("{1} cannot be set for {0} entries")]
+    UnsupportedField(RecordType, &'static str),
+    #[error("improper content template: {0}")]
+    TemplateError(#[from]TemplateError),
+    #[error("improper prefix length {0}, expected at most 128")]
+    PrefixLength(u8),
+    #[error("quorum {quorum} of the IP address providers must be between 1 and the number of providers ({providers})")]
+    InvalidQuorum { quorum : usize, providers : usize }
+}
+
+#[derive(Error, Debug)]
+pub enum TemplateError {
+    #[error("unknown placeholder {{{0}}}, expected {{ipv4}} or {{ipv6}}")]
+    UnknownPlaceholder(String),
+    #[error("unmatched brace, use {{{{ or }}}} for literal braces")]
+    Unmatched
 }
 
 #[derive(Serialize, Deserialize, Clone, Error, Debug)]
@@ -26,4 +106,42 @@ pub enum UpdateError {
 pub struct CloudFlareError {
     pub code : u32,
     pub message : String,
-}
\ No newline at end of file
+}
+
+#[derive(Error, Debug)]
+pub enum IpSourceError {
+    #[error("cannot reach the IP address provider: {0}")]
+    ReqError(#[from]reqwest::Error),
+    #[error("IP address provider responded with status {0}")]
+    StatusError(StatusCode),
+    #[error("IP address provider response is not valid JSON: {0}")]
+    JsonError(#[from]JsonError),
+    #[error("IP address provider response has no string at JSON pointer {0:?}")]
+    PointerError(String),
+    #[error("{0:?} is not a valid IPv4 or IPv6 address")]
+    InvalidAddress(String),
+    #[error("cannot read the addresses of the network interface: {0}")]
+    InterfaceError(IoError),
+    #[error("cannot communicate with the IP address provider: {0}")]
+    SocketError(#[from]IoError),
+    #[error("cannot run command {command}: {source}")]
+    SpawnError { command : String, source : IoError },
+    #[error("command exited unsuccessfully ({0})")]
+    CommandFailed(ExitStatus),
+    #[error("gateway error: {0}")]
+    GatewayError(String),
+    #[error("IP address provider did not respond in time")]
+    Timeout,
+    #[error("{0} is not supported on this platform")]
+    Unsupported(&'static str),
+    #[error("IP address provider did not return an address of the requested family")]
+    NoAddress
+}
+
+impl IpSourceError {
+    /// Whether the provider may succeed if it is asked again later, as opposed to needing a change
+    /// to its configuration
+    pub fn is_transient(&self) -> bool {
+        !matches!(self, Self::JsonError(_) | Self::PointerError(_) | Self::InvalidAddress(_) | Self::SpawnError { .. } | Self::CommandFailed(_) | Self::Unsupported(_))
+    }
+}
@@ -0,0 +1,348 @@
+use std::{cmp::Reverse, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr}};
+
+use futures::future;
+use public_ip::{dns::{QueryMethod, Resolver}, Version};
+use tokio::{net::UdpSocket, time::{self, Duration}};
+
+mod command;
+mod gateway;
+mod interface;
+mod stun;
+
+use crate::{data::{DnsQuery, IpFamily, IpSource, IpSources, ResponseFormat}, error::IpSourceError};
+
+/// The initial retransmission interval of UDP requests, doubled after every attempt
+const INITIAL_INTERVAL : Duration = Duration::from_millis(250);
+const MAX_ATTEMPTS : u32 = 4;
+
+/// The detected public IPv4 and IPv6 addresses
+pub type Addrs = (Option<Ipv4Addr>, Option<Ipv6Addr>);
+
+/// Detects the public addresses using the configured providers, falling back to the `public_ip`
+/// backend when none are configured.
+pub async fn detect(sources : &IpSources, http : &reqwest::Client) -> Addrs {
+    if sources.providers.is_empty() {
+        return (public_ip::addr_v4().await, public_ip::addr_v6().await);
+    }
+
+    let (v4, v6) = match sources.quorum {
+        Some(quorum) => future::join(
+            consensus(&sources.providers, http, IpFamily::Ipv4, quorum),
+            consensus(&sources.providers, http, IpFamily::Ipv6, quorum)
+        ).await,
+        None => (
+            first_of(&sources.providers, http, IpFamily::Ipv4).await,
+            first_of(&sources.providers, http, IpFamily::Ipv6).await
+        )
+    };
+
+    let v4 = match v4 {
+        Some(IpAddr::V4(v)) => Some(v),
+        _ => None
+    };
+    let v6 = match v6 {
+        Some(IpAddr::V6(v)) => Some(v),
+        _ => None
+    };
+
+    (v4, v6)
+}
+
+/// Queries every provider concurrently, returning the address of `family` reported by at least
+/// `quorum` of them.
+async fn consensus(providers : &[IpSource], http : &reqwest::Client, family : IpFamily, quorum : usize) -> Option<IpAddr> {
+    let providers : Vec<&IpSource> = providers.iter().filter(|v| supports(v, family)).collect();
+    let results = future::join_all(providers.iter().map(|v| query(v, http, family))).await;
+
+    for (source, result) in providers.iter().zip(results.iter()) {
+        if let Err(e) = result {
+            log_failure(source, e);
+        }
+    }
+
+    vote(results, family, quorum)
+}
+
+/// Picks the address reported by the most providers from their `results`, as long as no other address was
+/// reported as often and at least `quorum` of them agree on it.
+fn vote(results : Vec<Result<IpAddr, IpSourceError>>, family : IpFamily, quorum : usize) -> Option<IpAddr> {
+    let total = results.len();
+    let mut votes : Vec<(IpAddr, usize)> = Vec::new();
+
+    for addr in results.into_iter().flatten() {
+        match votes.iter_mut().find(|(v, _)| *v == addr) {
+            Some((_, count)) => *count += 1,
+            None => votes.push((addr, 1))
+        }
+    }
+
+    votes.sort_by_key(|v| Reverse(v.1));
+
+    if votes.len() > 1 {
+        tracing::warn!("IP address providers disagree on the public {:?} address: {:?}", family, votes);
+    }
+
+    match votes.as_slice() {
+        [] => None,
+        [(_, a), (_, b), ..] if a == b => {
+            tracing::warn!("No {:?} address was reported by more providers than any other. Ignoring the results.", family);
+            None
+        }
+        [(addr, count), ..] if *count < quorum => {
+            tracing::warn!("Only {} of {} IP address providers reported {} while a quorum of {} is required. Ignoring the address.", count, total, addr, quorum);
+            None
+        }
+        [(addr, _), ..] => Some(*addr)
+    }
+}
+
+/// Queries `providers` in order, returning the first address of `family` found.
+async fn first_of(providers : &[IpSource], http : &reqwest::Client, family : IpFamily) -> Option<IpAddr> {
+    for source in providers.iter().filter(|v| supports(v, family)) {
+        match query(source, http, family).await {
+            Ok(v) => return Some(v),
+            Err(e) => log_failure(source, &e)
+        }
+    }
+
+    None
+}
+
+fn log_failure(source : &IpSource, e : &IpSourceError) {
+    if e.is_transient() {
+        tracing::debug!("IP address provider {:?} failed with error {}", source, e);
+    } else {
+        tracing::warn!("IP address provider {:?} failed with error {}", source, e);
+    }
+}
+
+/// Whether `source` can be asked for an address of `family`.
+pub fn supports(source : &IpSource, family : IpFamily) -> bool {
+    match source {
+        IpSource::Http { family: Some(v), .. } | IpSource::Command { family: Some(v), .. } => *v == family,
+        IpSource::Upnp | IpSource::NatPmp { .. } | IpSource::Pcp { gateway: None } => family == IpFamily::Ipv4,
+        // PCP servers report an external address of the same family as the request
+        IpSource::Pcp { gateway: Some(v) } => family_of(v) == family,
+        _ => true
+    }
+}
+
+/// Asks a single provider for the public address of `family`.
+pub async fn query(source : &IpSource, http : &reqwest::Client, family : IpFamily) -> Result<IpAddr, IpSourceError> {
+    let addr = match source {
+        IpSource::PublicIp => {
+            public_ip::addr_with(public_ip::ALL, version(family)).await.ok_or(IpSourceError::NoAddress)?
+        }
+        IpSource::Http { url, format, pointer, .. } => {
+            query_http(http, url, *format, pointer.as_deref()).await?
+        }
+        IpSource::Dns { name, servers, port, query } => {
+            let method = match (query, family) {
+                (DnsQuery::Address, IpFamily::Ipv4) => QueryMethod::A,
+                (DnsQuery::Address, IpFamily::Ipv6) => QueryMethod::AAAA,
+                (DnsQuery::Txt, _) => QueryMethod::TXT
+            };
+            let resolver = Resolver::new(name.clone(), servers.clone(), *port, method);
+
+            public_ip::addr_with(resolver, version(family)).await.ok_or(IpSourceError::NoAddress)?
+        }
+        IpSource::Interface { interface, filter } => {
+            interface::query(interface, family, filter)?
+        }
+        IpSource::Upnp => {
+            gateway::upnp(http).await?
+        }
+        IpSource::NatPmp { gateway } => {
+            gateway::nat_pmp(*gateway).await?
+        }
+        IpSource::Pcp { gateway } => {
+            gateway::pcp(*gateway).await?
+        }
+        IpSource::Stun { servers } => {
+            stun::query(servers, family).await?
+        }
+        IpSource::Command { command, args, timeout, .. } => {
+            command::query(command, args, *timeout, family).await?
+        }
+    };
+
+    if family_of(&addr) != family {
+        return Err(IpSourceError::NoAddress);
+    }
+
+    Ok(addr)
+}
+
+async fn query_http(http : &reqwest::Client, url : &str, format : ResponseFormat, pointer : Option<&str>) -> Result<IpAddr, IpSourceError> {
+    let v = http.get(url).send().await?;
+
+    if !v.status().is_success() {
+        return Err(IpSourceError::StatusError(v.status()));
+    }
+
+    let body = v.text().await?;
+
+    let text = match format {
+        ResponseFormat::Text => body,
+        ResponseFormat::Json => {
+            let value : serde_json::Value = serde_json::from_str(&body)?;
+            let pointer = pointer.unwrap_or("");
+
+            match value.pointer(pointer).and_then(|v| v.as_str()) {
+                Some(v) => v.to_owned(),
+                None => return Err(IpSourceError::PointerError(pointer.to_owned()))
+            }
+        }
+    };
+
+    parse_addr(&text)
+}
+
+/// Parses an address surrounded by optional whitespace.
+pub fn parse_addr(text : &str) -> Result<IpAddr, IpSourceError> {
+    let text = text.trim();
+
+    text.parse().map_err(|_| IpSourceError::InvalidAddress(text.to_owned()))
+}
+
+/// Combines the first `prefix_length` bits of `prefix` with the remaining bits of `suffix`, such as
+/// a delegated prefix with the interface identifier of a host.
+pub fn with_suffix(prefix : Ipv6Addr, suffix : Ipv6Addr, prefix_length : u8) -> Ipv6Addr {
+    let mask = u128::MAX.checked_shl(128 - u32::from(prefix_length.min(128))).unwrap_or(0);
+
+    Ipv6Addr::from((u128::from(prefix) & mask) | (u128::from(suffix) & !mask))
+}
+
+pub fn family_of(addr : &IpAddr) -> IpFamily {
+    match addr {
+        IpAddr::V4(_) => IpFamily::Ipv4,
+        IpAddr::V6(_) => IpFamily::Ipv6
+    }
+}
+
+fn version(family : IpFamily) -> Version {
+    match family {
+        IpFamily::Ipv4 => Version::V4,
+        IpFamily::Ipv6 => Version::V6
+    }
+}
+
+/// Sends `req` over UDP to `server` until a response accepted by `valid` arrives.
+async fn exchange(server : SocketAddr, req : &[u8], valid : impl Fn(&[u8]) -> bool) -> Result<Vec<u8>, IpSourceError> {
+    let socket = bind_for(server).await?;
+    socket.connect(server).await?;
+
+    exchange_on(&socket, req, valid).await
+}
+
+async fn exchange_on(socket : &UdpSocket, req : &[u8], valid : impl Fn(&[u8]) -> bool) -> Result<Vec<u8>, IpSourceError> {
+    let mut buf = [0u8; 1100];
+    let mut interval = INITIAL_INTERVAL;
+
+    for _ in 0..MAX_ATTEMPTS {
+        socket.send(req).await?;
+
+        let res = time::timeout(interval, async {
+            loop {
+                let len = socket.recv(&mut buf).await?;
+
+                if valid(&buf[..len]) {
+                    return Ok::<_, std::io::Error>(buf[..len].to_vec());
+                }
+            }
+        }).await;
+
+        match res {
+            Ok(v) => return Ok(v?),
+            Err(_) => interval *= 2
+        }
+    }
+
+    Err(IpSourceError::Timeout)
+}
+
+async fn bind_for(server : SocketAddr) -> Result<UdpSocket, IpSourceError> {
+    let local : SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+
+    Ok(UdpSocket::bind(local).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ok(addr : &str) -> Result<IpAddr, IpSourceError> {
+        Ok(addr.parse().unwrap())
+    }
+
+    fn sources(config : &str) -> IpSources {
+        toml::from_str(config).unwrap()
+    }
+
+    #[test]
+    fn majority_wins() {
+        let results = vec![ok("203.0.113.1"), ok("198.51.100.1"), ok("203.0.113.1")];
+
+        assert_eq!(vote(results, IpFamily::Ipv4, 2), Some("203.0.113.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn failures_do_not_vote() {
+        let results = vec![ok("203.0.113.1"), Err(IpSourceError::Timeout), Err(IpSourceError::NoAddress), ok("203.0.113.1")];
+
+        assert_eq!(vote(results, IpFamily::Ipv4, 2), Some("203.0.113.1".parse().unwrap()));
+        assert_eq!(vote(vec![Err(IpSourceError::Timeout)], IpFamily::Ipv4, 1), None);
+        assert_eq!(vote(Vec::new(), IpFamily::Ipv4, 1), None);
+    }
+
+    #[test]
+    fn ties_are_ignored() {
+        let results = vec![ok("203.0.113.1"), ok("198.51.100.1"), ok("198.51.100.1"), ok("203.0.113.1")];
+
+        assert_eq!(vote(results, IpFamily::Ipv4, 1), None);
+    }
+
+    #[test]
+    fn addresses_below_quorum_are_ignored() {
+        let results = vec![ok("203.0.113.1"), ok("203.0.113.1"), ok("198.51.100.1"), Err(IpSourceError::Timeout)];
+
+        assert_eq!(vote(results, IpFamily::Ipv4, 3), None);
+    }
+
+    #[test]
+    fn unsupported_providers_are_not_asked() {
+        let sources = sources(r#"
+            [[providers]]
+            type = "upnp"
+
+            [[providers]]
+            type = "nat_pmp"
+
+            [[providers]]
+            type = "http"
+            url = "https://example.com"
+            family = "ipv6"
+
+            [[providers]]
+            type = "public_ip"
+        "#);
+
+        let count = |family| sources.providers.iter().filter(|v| supports(v, family)).count();
+
+        assert_eq!(count(IpFamily::Ipv4), 3);
+        assert_eq!(count(IpFamily::Ipv6), 2);
+    }
+
+    #[test]
+    fn quorum_must_be_reachable() {
+        let providers = "[[providers]]\ntype = \"public_ip\"\n\n[[providers]]\ntype = \"upnp\"\n";
+
+        assert_eq!(sources(&format!("quorum = 0\n{}", providers)).invalid_quorum(), Some(0));
+        assert_eq!(sources(&format!("quorum = 3\n{}", providers)).invalid_quorum(), Some(3));
+        assert_eq!(sources(&format!("quorum = 2\n{}", providers)).invalid_quorum(), None);
+        assert_eq!(sources(providers).invalid_quorum(), None);
+    }
+}
@@ -0,0 +1,66 @@
+use std::{net::IpAddr, process::Stdio};
+
+use tokio::{process::Command, time::{self, Duration}};
+
+use crate::{data::IpFamily, error::IpSourceError};
+
+use super::{family_of, parse_addr};
+
+/// Runs `command` and parses every non-empty line of its output as an address, returning the first
+/// one of `family`. The wanted family is passed to the command in the `CFDDNS_FAMILY` variable.
+pub async fn query(command : &str, args : &[String], timeout : u64, family : IpFamily) -> Result<IpAddr, IpSourceError> {
+    let child = Command::new(command)
+        .args(args)
+        .env("CFDDNS_FAMILY", match family {
+            IpFamily::Ipv4 => "ipv4",
+            IpFamily::Ipv6 => "ipv6"
+        })
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| IpSourceError::SpawnError { command: command.to_owned(), source: e })?;
+
+    let output = time::timeout(Duration::from_millis(timeout), child.wait_with_output())
+        .await
+        .map_err(|_| IpSourceError::Timeout)??;
+
+    if !output.status.success() {
+        return Err(IpSourceError::CommandFailed(output.status));
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    for line in stdout.lines().filter(|v| !v.trim().is_empty()) {
+        let addr = parse_addr(line)?;
+
+        if family_of(&addr) == family {
+            return Ok(addr);
+        }
+    }
+
+    Err(IpSourceError::NoAddress)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn missing_command_is_not_transient() {
+        let e = query("cloudflareddns-missing-command", &[], 1000, IpFamily::Ipv4).await.unwrap_err();
+
+        assert!(matches!(&e, IpSourceError::SpawnError { command, .. } if command == "cloudflareddns-missing-command"));
+        assert!(!e.is_transient());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn returns_first_address_of_family() {
+        let args = ["-c".to_owned(), "echo; echo 2001:db8::1; echo 203.0.113.1".to_owned()];
+
+        assert_eq!(query("sh", &args, 1000, IpFamily::Ipv4).await.unwrap(), "203.0.113.1".parse::<IpAddr>().unwrap());
+        assert_eq!(query("sh", &args, 1000, IpFamily::Ipv6).await.unwrap(), "2001:db8::1".parse::<IpAddr>().unwrap());
+    }
+}
@@ -0,0 +1,428 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use reqwest::{header, Url};
+use tokio::{net::UdpSocket, time::{self, Duration}};
+
+use crate::error::IpSourceError;
+
+use super::{bind_for, exchange, exchange_on};
+
+/// The port NAT-PMP and PCP servers listen on
+const NAT_PMP_PORT : u16 = 5351;
+
+const SSDP_ADDR : &str = "239.255.255.250:1900";
+const SSDP_TIMEOUT : Duration = Duration::from_secs(3);
+const WAN_SERVICES : [&str; 2] = ["WANIPConnection", "WANPPPConnection"];
+
+/// Asks the gateway for its external IPv4 address with a NAT-PMP (RFC 6886) external address request.
+pub async fn nat_pmp(gateway : Option<Ipv4Addr>) -> Result<IpAddr, IpSourceError> {
+    let gateway = match gateway {
+        Some(v) => v,
+        None => default_gateway()?
+    };
+
+    nat_pmp_at(SocketAddr::new(IpAddr::V4(gateway), NAT_PMP_PORT)).await
+}
+
+async fn nat_pmp_at(server : SocketAddr) -> Result<IpAddr, IpSourceError> {
+    let res = exchange(server, &[0, 0], |v| v.len() >= 12 && v[0] == 0 && v[1] == 128).await?;
+
+    let result = u16::from_be_bytes([res[2], res[3]]);
+
+    if result != 0 {
+        return Err(IpSourceError::GatewayError(format!("NAT-PMP gateway returned result code {}", result)));
+    }
+
+    Ok(IpAddr::V4(Ipv4Addr::new(res[8], res[9], res[10], res[11])))
+}
+
+/// Asks the gateway for its external address with a short-lived PCP (RFC 6887) MAP request, which
+/// expires on its own.
+pub async fn pcp(gateway : Option<IpAddr>) -> Result<IpAddr, IpSourceError> {
+    let gateway = match gateway {
+        Some(v) => v,
+        None => IpAddr::V4(default_gateway()?)
+    };
+
+    pcp_at(SocketAddr::new(gateway, NAT_PMP_PORT)).await
+}
+
+async fn pcp_at(server : SocketAddr) -> Result<IpAddr, IpSourceError> {
+    const VERSION : u8 = 2;
+    const OPCODE_MAP : u8 = 1;
+    const LIFETIME : u32 = 30;
+    const UDP : u8 = 17;
+
+    let socket = bind_for(server).await?;
+    socket.connect(server).await?;
+    let local = socket.local_addr()?;
+
+    let nonce : [u8; 12] = std::array::from_fn(|_| fastrand::u8(..));
+
+    let mut req = Vec::with_capacity(60);
+    req.extend_from_slice(&[VERSION, OPCODE_MAP, 0, 0]);
+    req.extend_from_slice(&LIFETIME.to_be_bytes());
+    req.extend_from_slice(&to_pcp_addr(local.ip()).octets());
+    req.extend_from_slice(&nonce);
+    req.extend_from_slice(&[UDP, 0, 0, 0]);
+    req.extend_from_slice(&local.port().to_be_bytes());
+    // No suggested external port or address
+    req.extend_from_slice(&[0, 0]);
+    req.extend_from_slice(&to_pcp_addr(match local.ip() {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+    }).octets());
+
+    let res = exchange_on(&socket, &req, |v| v.len() >= 60 && v[0] == VERSION && v[1] == 0x80 | OPCODE_MAP && v[24..36] == nonce).await?;
+
+    if res[3] != 0 {
+        return Err(IpSourceError::GatewayError(format!("PCP server returned result code {}", res[3])));
+    }
+
+    let addr : [u8; 16] = res[44..60].try_into().expect("slice has 16 bytes");
+    let addr = Ipv6Addr::from(addr);
+
+    Ok(match addr.to_ipv4_mapped() {
+        Some(v) => IpAddr::V4(v),
+        None => IpAddr::V6(addr)
+    })
+}
+
+/// Asks the Internet Gateway Device found with SSDP for its external IPv4 address through the
+/// `GetExternalIPAddress` action of its WAN connection service.
+pub async fn upnp(http : &reqwest::Client) -> Result<IpAddr, IpSourceError> {
+    let location = discover_igd(SSDP_ADDR).await?;
+
+    upnp_at(http, location).await
+}
+
+/// Asks the Internet Gateway Device described at `location` for its external IPv4 address.
+async fn upnp_at(http : &reqwest::Client, location : Url) -> Result<IpAddr, IpSourceError> {
+    let description = http.get(location.clone()).send().await?.text().await?;
+
+    let (service_type, control_url) = description.split("<service>")
+        .skip(1)
+        .filter_map(|v| Some((tag(v, "serviceType")?, tag(v, "controlURL")?)))
+        .find(|(service_type, _)| WAN_SERVICES.iter().any(|v| service_type.contains(v)))
+        .ok_or_else(|| IpSourceError::GatewayError("gateway has no WAN connection service".to_owned()))?;
+
+    let control_url = location.join(control_url)
+        .map_err(|e| IpSourceError::GatewayError(format!("invalid control URL {:?}: {}", control_url, e)))?;
+
+    let body = format!(concat!(
+        r#"<?xml version="1.0"?>"#,
+        r#"<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">"#,
+        r#"<s:Body><u:GetExternalIPAddress xmlns:u="{}"></u:GetExternalIPAddress></s:Body>"#,
+        r#"</s:Envelope>"#
+    ), service_type);
+
+    let res = http.post(control_url)
+        .header(header::CONTENT_TYPE, r#"text/xml; charset="utf-8""#)
+        .header("SOAPAction", format!(r#""{}#GetExternalIPAddress""#, service_type))
+        .body(body)
+        .send()
+        .await?;
+
+    if !res.status().is_success() {
+        return Err(IpSourceError::StatusError(res.status()));
+    }
+
+    let res = res.text().await?;
+
+    match tag(&res, "NewExternalIPAddress") {
+        Some(v) => super::parse_addr(v),
+        None => Err(IpSourceError::GatewayError("gateway response has no external IP address".to_owned()))
+    }
+}
+
+/// Finds the description URL of an Internet Gateway Device by sending an SSDP search to `target`, the SSDP
+/// multicast address outside of tests.
+async fn discover_igd(target : &str) -> Result<Url, IpSourceError> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    let req = concat!(
+        "M-SEARCH * HTTP/1.1\r\n",
+        "HOST: 239.255.255.250:1900\r\n",
+        "ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n",
+        "MAN: \"ssdp:discover\"\r\n",
+        "MX: 2\r\n\r\n"
+    );
+
+    socket.send_to(req.as_bytes(), target).await?;
+
+    let mut buf = [0u8; 2048];
+
+    let location = time::timeout(SSDP_TIMEOUT, async {
+        loop {
+            let len = socket.recv(&mut buf).await?;
+            let res = String::from_utf8_lossy(&buf[..len]);
+
+            let location = res.lines()
+                .filter_map(|v| v.split_once(':'))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("location"))
+                .map(|(_, value)| value.trim().to_owned());
+
+            if let Some(v) = location {
+                return Ok::<_, IpSourceError>(v);
+            }
+        }
+    }).await.map_err(|_| IpSourceError::Timeout)??;
+
+    Url::parse(&location).map_err(|e| IpSourceError::GatewayError(format!("invalid device location {:?}: {}", location, e)))
+}
+
+/// Returns the text inside the first `<name>` element of `xml`.
+fn tag<'a>(xml : &'a str, name : &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", name))? + name.len() + 2;
+    let end = xml[start..].find(&format!("</{}>", name))? + start;
+
+    Some(xml[start..end].trim())
+}
+
+/// PCP carries IPv4 addresses as IPv4-mapped IPv6 addresses.
+fn to_pcp_addr(addr : IpAddr) -> Ipv6Addr {
+    match addr {
+        IpAddr::V4(v) => v.to_ipv6_mapped(),
+        IpAddr::V6(v) => v
+    }
+}
+
+/// Reads the IPv4 default gateway from the routing table.
+#[cfg(target_os = "linux")]
+fn default_gateway() -> Result<Ipv4Addr, IpSourceError> {
+    let routes = std::fs::read_to_string("/proc/net/route").map_err(IpSourceError::InterfaceError)?;
+
+    // Each line is "<interface> <destination> <gateway> ..." with addresses in little endian hexadecimal
+    routes.lines()
+        .skip(1)
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .filter(|fields| fields.len() > 2 && fields[1] == "00000000" && fields[2] != "00000000")
+        .find_map(|fields| u32::from_str_radix(fields[2], 16).ok())
+        .map(|v| Ipv4Addr::from(u32::from_be(v)))
+        .ok_or_else(|| IpSourceError::GatewayError("no default gateway found".to_owned()))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn default_gateway() -> Result<Ipv4Addr, IpSourceError> {
+    Err(IpSourceError::Unsupported("finding the default gateway without a configured gateway address"))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader}, net::TcpListener, sync::oneshot};
+
+    use super::*;
+
+    const WAN_IP : &str = "urn:schemas-upnp-org:service:WANIPConnection:1";
+
+    /// Starts a server on the loopback interface which answers one datagram with the response `respond` builds
+    /// from it, passing the datagram on to the test.
+    async fn server(respond : impl FnOnce(&[u8]) -> Vec<u8> + Send + 'static) -> (SocketAddr, oneshot::Receiver<Vec<u8>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let (send, recv) = oneshot::channel();
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1100];
+            let (n, from) = socket.recv_from(&mut buf).await.unwrap();
+
+            socket.send_to(&respond(&buf[..n]), from).await.unwrap();
+            let _ = send.send(buf[..n].to_vec());
+        });
+
+        (addr, recv)
+    }
+
+    /// Starts an HTTP server on the loopback interface which answers a request with each of `responses` in
+    /// turn, passing the requests with their bodies on to the test.
+    async fn http_server(responses : Vec<String>) -> (Url, oneshot::Receiver<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        let (send, recv) = oneshot::channel();
+
+        tokio::spawn(async move {
+            let mut requests = Vec::new();
+
+            for response in responses {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut stream = BufReader::new(stream);
+                let mut request = String::new();
+                let mut len = 0;
+
+                loop {
+                    let mut line = String::new();
+                    stream.read_line(&mut line).await.unwrap();
+
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            len = value.trim().parse().unwrap();
+                        }
+                    }
+
+                    request.push_str(&line);
+
+                    if line == "\r\n" {
+                        break;
+                    }
+                }
+
+                let mut body = vec![0u8; len];
+                stream.read_exact(&mut body).await.unwrap();
+                request.push_str(&String::from_utf8_lossy(&body));
+                requests.push(request);
+
+                let mut stream = stream.into_inner();
+                stream.write_all(response.as_bytes()).await.unwrap();
+                stream.shutdown().await.unwrap();
+            }
+
+            let _ = send.send(requests);
+        });
+
+        (url, recv)
+    }
+
+    fn xml(status : &str, body : &str) -> String {
+        format!("HTTP/1.1 {}\r\nContent-Type: text/xml\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body)
+    }
+
+    fn description(services : &[(&str, &str)]) -> String {
+        let services : String = services.iter()
+            .map(|(service_type, control_url)| format!("<service>\n<serviceType>{}</serviceType>\n<controlURL>{}</controlURL>\n</service>\n", service_type, control_url))
+            .collect();
+
+        xml("200 OK", &format!("<?xml version=\"1.0\"?>\n<root><device><serviceList>\n{}</serviceList></device></root>", services))
+    }
+
+    fn external_address(addr : &str) -> String {
+        xml("200 OK", &format!(concat!(
+            r#"<?xml version="1.0"?>"#,
+            r#"<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/"><s:Body>"#,
+            r#"<u:GetExternalIPAddressResponse xmlns:u="{}"><NewExternalIPAddress>{}</NewExternalIPAddress></u:GetExternalIPAddressResponse>"#,
+            r#"</s:Body></s:Envelope>"#
+        ), WAN_IP, addr))
+    }
+
+    fn nat_pmp_response(result : u16, addr : Ipv4Addr) -> Vec<u8> {
+        let mut res = vec![0, 128];
+        res.extend_from_slice(&result.to_be_bytes());
+        // Seconds since the mapping table was initialized
+        res.extend_from_slice(&1000u32.to_be_bytes());
+        res.extend_from_slice(&addr.octets());
+        res
+    }
+
+    fn pcp_response(req : &[u8], result : u8, addr : IpAddr) -> Vec<u8> {
+        let mut res = vec![0u8; 60];
+        res[0] = 2;
+        res[1] = 0x80 | req[1];
+        res[3] = result;
+        // The nonce, protocol and internal port of the request
+        res[24..42].copy_from_slice(&req[24..42]);
+        res[42..44].copy_from_slice(&40000u16.to_be_bytes());
+        res[44..60].copy_from_slice(&to_pcp_addr(addr).octets());
+        res
+    }
+
+    #[tokio::test]
+    async fn nat_pmp_external_address() {
+        let (server, req) = server(|_| nat_pmp_response(0, Ipv4Addr::new(203, 0, 113, 7))).await;
+
+        assert_eq!(nat_pmp_at(server).await.unwrap(), IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7)));
+        assert_eq!(req.await.unwrap(), [0, 0]);
+    }
+
+    #[tokio::test]
+    async fn nat_pmp_result_code() {
+        // Network failure
+        let (server, _) = server(|_| nat_pmp_response(3, Ipv4Addr::UNSPECIFIED)).await;
+
+        assert!(matches!(nat_pmp_at(server).await, Err(IpSourceError::GatewayError(_))));
+    }
+
+    #[tokio::test]
+    async fn pcp_ipv4_mapped_address() {
+        let (server, req) = server(|req| pcp_response(req, 0, IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7)))).await;
+
+        assert_eq!(pcp_at(server).await.unwrap(), IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7)));
+
+        let req = req.await.unwrap();
+        assert_eq!(req.len(), 60);
+        assert_eq!(req[..2], [2, 1]);
+        // The lifetime and the internal address of the mapping
+        assert_eq!(req[4..8], 30u32.to_be_bytes());
+        assert_eq!(req[8..24], Ipv4Addr::LOCALHOST.to_ipv6_mapped().octets());
+    }
+
+    #[tokio::test]
+    async fn pcp_ipv6_address() {
+        let addr = IpAddr::V6("2001:db8::7".parse().unwrap());
+        let (server, req) = server(move |req| pcp_response(req, 0, addr)).await;
+
+        assert_eq!(pcp_at(server).await.unwrap(), addr);
+        assert_eq!(req.await.unwrap().len(), 60);
+    }
+
+    #[tokio::test]
+    async fn pcp_result_code() {
+        // NOT_AUTHORIZED
+        let (server, _) = server(|req| pcp_response(req, 2, IpAddr::V4(Ipv4Addr::UNSPECIFIED))).await;
+
+        assert!(matches!(pcp_at(server).await, Err(IpSourceError::GatewayError(_))));
+    }
+
+    #[tokio::test]
+    async fn discovers_gateway_location() {
+        let (server, req) = server(|_| b"HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\nLocation: http://192.168.1.1:5000/rootDesc.xml\r\n\r\n".to_vec()).await;
+
+        let location = discover_igd(&server.to_string()).await.unwrap();
+
+        assert_eq!(location.as_str(), "http://192.168.1.1:5000/rootDesc.xml");
+
+        let req = String::from_utf8(req.await.unwrap()).unwrap();
+        assert!(req.starts_with("M-SEARCH * HTTP/1.1\r\n"));
+        assert!(req.contains("ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n"));
+    }
+
+    #[tokio::test]
+    async fn upnp_external_address() {
+        let (url, requests) = http_server(vec![
+            description(&[("urn:schemas-upnp-org:service:Layer3Forwarding:1", "/ctl/L3F"), (WAN_IP, "/ctl/IPConn")]),
+            external_address("203.0.113.7")
+        ]).await;
+
+        let addr = upnp_at(&reqwest::Client::new(), url.join("rootDesc.xml").unwrap()).await.unwrap();
+
+        assert_eq!(addr, IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7)));
+
+        let requests = requests.await.unwrap();
+        assert!(requests[0].starts_with("GET /rootDesc.xml HTTP/1.1\r\n"));
+        assert!(requests[1].starts_with("POST /ctl/IPConn HTTP/1.1\r\n"));
+        assert!(requests[1].to_lowercase().contains(&format!("soapaction: \"{}#getexternalipaddress\"\r\n", WAN_IP.to_lowercase())));
+        assert!(requests[1].contains(&format!(r#"<u:GetExternalIPAddress xmlns:u="{}">"#, WAN_IP)));
+    }
+
+    #[tokio::test]
+    async fn upnp_without_wan_service() {
+        let (url, _) = http_server(vec![description(&[("urn:schemas-upnp-org:service:Layer3Forwarding:1", "/ctl/L3F")])]).await;
+
+        assert!(matches!(upnp_at(&reqwest::Client::new(), url).await, Err(IpSourceError::GatewayError(_))));
+    }
+
+    #[tokio::test]
+    async fn upnp_soap_fault() {
+        let (url, _) = http_server(vec![description(&[(WAN_IP, "/ctl/IPConn")]), xml("500 Internal Server Error", "<s:Fault/>")]).await;
+
+        assert!(matches!(upnp_at(&reqwest::Client::new(), url).await, Err(IpSourceError::StatusError(_))));
+    }
+
+    #[test]
+    fn finds_tags() {
+        let xml = "<root><serviceType>\n  urn:a  \n</serviceType><controlURL>/ctl</controlURL><controlURL>/other</controlURL></root>";
+
+        assert_eq!(tag(xml, "serviceType"), Some("urn:a"));
+        assert_eq!(tag(xml, "controlURL"), Some("/ctl"));
+        assert_eq!(tag(xml, "eventSubURL"), None);
+        assert_eq!(tag("<controlURL>/ctl", "controlURL"), None);
+        assert_eq!(tag("<NewExternalIPAddress></NewExternalIPAddress>", "NewExternalIPAddress"), Some(""));
+    }
+}
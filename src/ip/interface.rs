@@ -0,0 +1,100 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::{data::{AddressFilter, IpFamily}, error::IpSourceError};
+
+use super::family_of;
+
+/// `IFA_F_TEMPORARY`, set on IPv6 privacy extension addresses
+const IFA_F_TEMPORARY : u32 = 0x01;
+/// `IFA_F_DEPRECATED`, set on addresses past their preferred lifetime
+const IFA_F_DEPRECATED : u32 = 0x20;
+
+/// Returns the first address of `family` assigned to `interface` that passes `filter`.
+pub fn query(interface : &str, family : IpFamily, filter : &AddressFilter) -> Result<IpAddr, IpSourceError> {
+    let flags = ipv6_flags(interface);
+
+    addresses(interface)?
+        .into_iter()
+        .filter(|v| family_of(v) == family && !v.is_loopback() && !v.is_unspecified())
+        .find(|v| match v {
+            IpAddr::V4(v) => accepts_v4(v, filter),
+            IpAddr::V6(v) => {
+                let flags = flags.iter().find(|(addr, _)| addr == v).map(|(_, flags)| *flags).unwrap_or(0);
+
+                accepts_v6(v, flags, filter)
+            }
+        })
+        .ok_or(IpSourceError::NoAddress)
+}
+
+fn accepts_v4(addr : &Ipv4Addr, filter : &AddressFilter) -> bool {
+    let octets = addr.octets();
+    // 100.64.0.0/10, used for carrier-grade NAT
+    let shared = octets[0] == 100 && (octets[1] & 0xc0) == 64;
+
+    !(filter.exclude_link_local && addr.is_link_local()
+        || filter.exclude_private && (addr.is_private() || shared))
+}
+
+fn accepts_v6(addr : &Ipv6Addr, flags : u32, filter : &AddressFilter) -> bool {
+    let first = addr.segments()[0];
+    let link_local = (first & 0xffc0) == 0xfe80;
+    let unique_local = (first & 0xfe00) == 0xfc00;
+
+    !(filter.exclude_link_local && link_local
+        || (filter.exclude_ula || filter.exclude_private) && unique_local
+        || filter.exclude_temporary && flags & IFA_F_TEMPORARY != 0
+        || filter.exclude_deprecated && flags & IFA_F_DEPRECATED != 0)
+}
+
+#[cfg(unix)]
+fn addresses(interface : &str) -> Result<Vec<IpAddr>, IpSourceError> {
+    let addrs = nix::ifaddrs::getifaddrs().map_err(|e| IpSourceError::InterfaceError(e.into()))?;
+
+    Ok(addrs
+        .filter(|v| v.interface_name == interface)
+        .filter_map(|v| v.address)
+        .filter_map(|v| {
+            if let Some(v) = v.as_sockaddr_in() {
+                Some(IpAddr::V4(v.ip()))
+            } else {
+                v.as_sockaddr_in6().map(|v| IpAddr::V6(v.ip()))
+            }
+        })
+        .collect())
+}
+
+#[cfg(not(unix))]
+fn addresses(_interface : &str) -> Result<Vec<IpAddr>, IpSourceError> {
+    Err(IpSourceError::Unsupported("reading interface addresses"))
+}
+
+/// Reads the flags of the IPv6 addresses of `interface`, which `getifaddrs` does not report.
+#[cfg(target_os = "linux")]
+fn ipv6_flags(interface : &str) -> Vec<(Ipv6Addr, u32)> {
+    let contents = match std::fs::read_to_string("/proc/net/if_inet6") {
+        Ok(v) => v,
+        Err(_) => return Vec::new()
+    };
+
+    // Each line is "<address> <index> <prefix length> <scope> <flags> <name>" with hexadecimal fields
+    contents.lines()
+        .filter_map(|line| {
+            let fields : Vec<&str> = line.split_whitespace().collect();
+
+            if fields.len() != 6 || fields[5] != interface || fields[0].len() != 32 {
+                return None;
+            }
+
+            let addr = u128::from_str_radix(fields[0], 16).ok()?;
+            let flags = u32::from_str_radix(fields[4], 16).ok()?;
+
+            Some((Ipv6Addr::from(addr), flags))
+        })
+        .collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn ipv6_flags(_interface : &str) -> Vec<(Ipv6Addr, u32)> {
+    Vec::new()
+}
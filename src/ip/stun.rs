@@ -0,0 +1,253 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::{data::IpFamily, error::IpSourceError};
+
+use super::{exchange, family_of};
+
+const BINDING_REQUEST : u16 = 0x0001;
+const BINDING_SUCCESS : u16 = 0x0101;
+const MAGIC_COOKIE : u32 = 0x2112a442;
+const MAPPED_ADDRESS : u16 = 0x0001;
+const XOR_MAPPED_ADDRESS : u16 = 0x0020;
+
+/// Asks STUN (RFC 5389) servers in order for the address a binding request was sent from. Only
+/// servers resolving to an address of `family` are used, and only mapped addresses of `family` accepted.
+pub async fn query(servers : &[String], family : IpFamily) -> Result<IpAddr, IpSourceError> {
+    let mut last = IpSourceError::NoAddress;
+
+    for server in servers {
+        let addrs = match tokio::net::lookup_host(server.as_str()).await {
+            Ok(v) => v,
+            Err(e) => {
+                last = e.into();
+                continue;
+            }
+        };
+
+        for addr in addrs.filter(|v| family_of(&v.ip()) == family) {
+            match binding(addr).await {
+                Ok(v) if family_of(&v) == family => return Ok(v),
+                Ok(v) => {
+                    tracing::debug!("STUN server {} ({}) returned {}, an address of the other family", server, addr, v);
+                    last = IpSourceError::NoAddress;
+                }
+                Err(e) => {
+                    tracing::debug!("STUN server {} ({}) failed with error {}", server, addr, e);
+                    last = e;
+                }
+            }
+        }
+    }
+
+    Err(last)
+}
+
+async fn binding(server : std::net::SocketAddr) -> Result<IpAddr, IpSourceError> {
+    let transaction : [u8; 12] = std::array::from_fn(|_| fastrand::u8(..));
+
+    let mut req = Vec::with_capacity(20);
+    req.extend_from_slice(&BINDING_REQUEST.to_be_bytes());
+    // No attributes
+    req.extend_from_slice(&0u16.to_be_bytes());
+    req.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    req.extend_from_slice(&transaction);
+
+    let res = exchange(server, &req, |v| {
+        v.len() >= 20
+            && u16::from_be_bytes([v[0], v[1]]) == BINDING_SUCCESS
+            && u32::from_be_bytes([v[4], v[5], v[6], v[7]]) == MAGIC_COOKIE
+            && v[8..20] == transaction
+    }).await?;
+
+    parse_response(&res, &transaction).ok_or(IpSourceError::NoAddress)
+}
+
+/// Extracts the mapped address from a binding success response, preferring `XOR-MAPPED-ADDRESS`.
+fn parse_response(res : &[u8], transaction : &[u8; 12]) -> Option<IpAddr> {
+    let len = (u16::from_be_bytes([res[2], res[3]]) as usize + 20).min(res.len());
+    let mut attrs = &res[20..len];
+    let mut mapped = None;
+
+    while attrs.len() >= 4 {
+        let kind = u16::from_be_bytes([attrs[0], attrs[1]]);
+        let attr_len = u16::from_be_bytes([attrs[2], attrs[3]]) as usize;
+        let value = attrs.get(4..4 + attr_len)?;
+
+        match kind {
+            XOR_MAPPED_ADDRESS => return parse_address(value, Some(transaction)),
+            MAPPED_ADDRESS => mapped = parse_address(value, None),
+            _ => {}
+        }
+
+        // Attributes are padded to a multiple of 4 bytes
+        let next = 4 + attr_len.div_ceil(4) * 4;
+        attrs = attrs.get(next..).unwrap_or_default();
+    }
+
+    mapped
+}
+
+/// Parses a `MAPPED-ADDRESS`, or a `XOR-MAPPED-ADDRESS` when the transaction ID is given.
+fn parse_address(value : &[u8], xor : Option<&[u8; 12]>) -> Option<IpAddr> {
+    let mut key = [0u8; 16];
+
+    if let Some(transaction) = xor {
+        key[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        key[4..].copy_from_slice(transaction);
+    }
+
+    // Reserved byte, family and port precede the address
+    match (value.get(1)?, value.len()) {
+        (0x01, 8) => {
+            let addr : [u8; 4] = std::array::from_fn(|i| value[4 + i] ^ key[i]);
+            Some(IpAddr::V4(Ipv4Addr::from(addr)))
+        }
+        (0x02, 20) => {
+            let addr : [u8; 16] = std::array::from_fn(|i| value[4 + i] ^ key[i]);
+            Some(IpAddr::V6(Ipv6Addr::from(addr)))
+        }
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::net::UdpSocket;
+
+    use super::*;
+
+    const TRANSACTION : [u8; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+    /// Encodes the value of a `MAPPED-ADDRESS`, or of a `XOR-MAPPED-ADDRESS` when the transaction ID is given.
+    fn address(addr : SocketAddr, xor : Option<&[u8; 12]>) -> Vec<u8> {
+        let mut key = [0u8; 16];
+
+        if let Some(transaction) = xor {
+            key[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+            key[4..].copy_from_slice(transaction);
+        }
+
+        let (family, octets) = match addr.ip() {
+            IpAddr::V4(v) => (0x01, v.octets().to_vec()),
+            IpAddr::V6(v) => (0x02, v.octets().to_vec())
+        };
+
+        let mut value = vec![0, family];
+        value.extend_from_slice(&(addr.port() ^ u16::from_be_bytes([key[0], key[1]])).to_be_bytes());
+        value.extend(octets.iter().enumerate().map(|(i, v)| v ^ key[i]));
+        value
+    }
+
+    fn response(transaction : &[u8; 12], attrs : &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let mut body = Vec::new();
+
+        for (kind, value) in attrs {
+            body.extend_from_slice(&kind.to_be_bytes());
+            body.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            body.extend_from_slice(value);
+            body.resize(body.len().div_ceil(4) * 4, 0);
+        }
+
+        let mut res = Vec::new();
+        res.extend_from_slice(&BINDING_SUCCESS.to_be_bytes());
+        res.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        res.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        res.extend_from_slice(transaction);
+        res.extend(body);
+        res
+    }
+
+    /// Starts a STUN server on `local` which answers one binding request with `mapped`.
+    async fn server(local : &str, mapped : SocketAddr) -> String {
+        let socket = UdpSocket::bind(local).await.unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1100];
+            let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+
+            assert_eq!(len, 20);
+            assert_eq!(u16::from_be_bytes([buf[0], buf[1]]), BINDING_REQUEST);
+
+            let transaction : [u8; 12] = buf[8..20].try_into().unwrap();
+            let res = response(&transaction, &[(XOR_MAPPED_ADDRESS, address(mapped, Some(&transaction)))]);
+
+            socket.send_to(&res, from).await.unwrap();
+        });
+
+        addr.to_string()
+    }
+
+    #[tokio::test]
+    async fn query_ipv4() {
+        let server = server("127.0.0.1:0", "203.0.113.7:40000".parse().unwrap()).await;
+
+        assert_eq!(query(&[server], IpFamily::Ipv4).await.unwrap(), "203.0.113.7".parse::<IpAddr>().unwrap());
+    }
+
+    #[tokio::test]
+    async fn query_ipv6() {
+        let server = server("[::1]:0", "[2001:db8::7]:40000".parse().unwrap()).await;
+
+        assert_eq!(query(&[server], IpFamily::Ipv6).await.unwrap(), "2001:db8::7".parse::<IpAddr>().unwrap());
+    }
+
+    #[tokio::test]
+    async fn rejects_addresses_of_other_family() {
+        let server = server("127.0.0.1:0", "[2001:db8::7]:40000".parse().unwrap()).await;
+
+        assert!(matches!(query(&[server], IpFamily::Ipv4).await, Err(IpSourceError::NoAddress)));
+    }
+
+    #[test]
+    fn prefers_xor_mapped_address() {
+        let mapped = address("192.0.2.1:1".parse().unwrap(), None);
+        let xor_mapped = address("203.0.113.7:2".parse().unwrap(), Some(&TRANSACTION));
+
+        let res = response(&TRANSACTION, &[(MAPPED_ADDRESS, mapped.clone()), (XOR_MAPPED_ADDRESS, xor_mapped)]);
+        assert_eq!(parse_response(&res, &TRANSACTION), Some("203.0.113.7".parse().unwrap()));
+
+        let res = response(&TRANSACTION, &[(MAPPED_ADDRESS, mapped)]);
+        assert_eq!(parse_response(&res, &TRANSACTION), Some("192.0.2.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn skips_unknown_attributes() {
+        // SOFTWARE, whose length is not a multiple of 4
+        let software = (0x8022, b"stun1".to_vec());
+        let mapped = address("[2001:db8::1]:3".parse().unwrap(), Some(&TRANSACTION));
+
+        let res = response(&TRANSACTION, &[software, (XOR_MAPPED_ADDRESS, mapped)]);
+        assert_eq!(parse_response(&res, &TRANSACTION), Some("2001:db8::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn rejects_truncated_attributes() {
+        let mapped = address("203.0.113.7:2".parse().unwrap(), Some(&TRANSACTION));
+        let mut res = response(&TRANSACTION, &[(XOR_MAPPED_ADDRESS, mapped)]);
+
+        // The attribute claims more bytes than the message has
+        res.truncate(res.len() - 2);
+        assert_eq!(parse_response(&res, &TRANSACTION), None);
+
+        assert_eq!(parse_response(&response(&TRANSACTION, &[]), &TRANSACTION), None);
+    }
+
+    #[test]
+    fn parses_addresses() {
+        let addr = "203.0.113.7:2".parse().unwrap();
+        assert_eq!(parse_address(&address(addr, None), None), Some(addr.ip()));
+        assert_eq!(parse_address(&address(addr, Some(&TRANSACTION)), Some(&TRANSACTION)), Some(addr.ip()));
+        assert_ne!(parse_address(&address(addr, Some(&TRANSACTION)), None), Some(addr.ip()));
+
+        let addr = "[2001:db8::7]:2".parse().unwrap();
+        assert_eq!(parse_address(&address(addr, Some(&TRANSACTION)), Some(&TRANSACTION)), Some(addr.ip()));
+
+        // Truncated addresses and unknown families
+        assert_eq!(parse_address(&address(addr, None)[..12], None), None);
+        assert_eq!(parse_address(&[0, 0x03, 0, 0, 1, 2, 3, 4], None), None);
+        assert_eq!(parse_address(&[0], None), None);
+    }
+}
@@ -0,0 +1,13 @@
+pub mod client;
+pub mod data;
+pub mod drift;
+pub mod environment;
+pub mod error;
+pub mod ip;
+pub mod netlink;
+pub mod plan;
+pub mod record;
+pub mod reload;
+pub mod secret;
+pub mod state;
+pub mod template;
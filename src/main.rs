@@ -1,202 +1,801 @@
 #![feature(async_closure)]
 
-use std::{net::{Ipv4Addr, Ipv6Addr}, sync::Arc, collections::HashMap};
+use std::{io::ErrorKind, path::{Path, PathBuf}, process::ExitCode, sync::Arc};
 
-use data::{Config, DnsUpdate, Response, DnsRecord, ExtendedResponse};
-use tokio::{fs::File, io::AsyncReadExt, time::{self, Duration}, sync::mpsc::{self, Sender}};
-use error::ConfigError;
+use clap::{Parser, Subcommand, ValueEnum};
+use cloudflareddns::{client::CloudflareClient, drift::{self, Drift}, data::{Config, DnsRecord, DnsUpdate, DomainInfo, Entry, EntryMode, IpSources, Settings}, environment, error::{ConfigError, UpdateError}, ip::{self, Addrs}, netlink, plan, record, reload, state::StateFile};
+use futures::future;
+use serde_json::Value;
+use tokio::{fs::File, io::AsyncReadExt, time::{self, Duration, Instant, Interval}, sync::mpsc::{self, Receiver, Sender}};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, Layer, filter};
 
-pub mod data;
-pub mod error;
+/// Time waited after an address change before the IP address is rechecked, in milliseconds
+const ADDRESS_SETTLE_TIME : u64 = 2000;
+/// Time waited after the config file changes before it is reloaded, in milliseconds
+const RELOAD_SETTLE_TIME : u64 = 500;
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Path of the config file
+    #[arg(short, long, global = true, default_value = "./Config.toml")]
+    config : PathBuf,
+    #[command(subcommand)]
+    command : Option<Command>
+}
 
-pub static ROOT : &str = "https://api.cloudflare.com/client/v4";
+#[derive(Subcommand)]
+enum Command {
+    /// Keep the records up to date until stopped (default)
+    Run,
+    /// Detect the IP addresses and update the records once, exiting unsuccessfully if any entry failed
+    Once {
+        /// Print what would change instead of changing any record
+        #[arg(long)]
+        dry_run : bool,
+        /// Format of the printed changes
+        #[arg(long, value_enum, default_value_t = Format::Human, requires = "dry_run")]
+        format : Format
+    },
+    /// Validate the config and check that the API tokens can access their zones
+    Check,
+    /// Print the DNS records of the zones in the config
+    List {
+        /// Only print the records of this zone ID
+        #[arg(long)]
+        zone : Option<String>
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    Human,
+    Json
+}
 
 #[tokio::main(flavor = "multi_thread")]
-async fn main() {
-    let subscriber = tracing_subscriber::fmt::layer().pretty();
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    // Logs go to standard error so that the output of commands such as list can be piped
+    let subscriber = tracing_subscriber::fmt::layer().pretty().with_writer(std::io::stderr);
     tracing_subscriber::registry().with(
         subscriber.with_filter(tracing_subscriber::filter::LevelFilter::TRACE)
         .with_filter(filter::filter_fn(|a| {
             a.target().starts_with("cloudflareddns")
         }))).init();
 
-    let config = read_to_config("./Config.toml").await;
-    let config = Arc::new(match config {
+    let config = read_to_config(&cli.config).await;
+    let config = match config {
         Ok(v) => v,
         Err(e) => {
             tracing::error!(cause = %e);
-            return;
+            return ExitCode::FAILURE;
         }
-    });
+    };
+
+    match cli.command.unwrap_or(Command::Run) {
+        Command::Run => run(config, &cli.config).await,
+        Command::Once { dry_run: false, .. } => once(config).await,
+        Command::Once { dry_run: true, format } => dry_run(config, format).await,
+        Command::Check => check(config).await,
+        Command::List { zone } => list(config, zone.as_deref()).await
+    }
+}
 
+async fn run(config : Config, path : &Path) -> ExitCode {
     tracing::info!("Starting IP polling loop");
 
-    let client = Arc::new(reqwest::Client::new());
+    let mut runner = Runner::new(reqwest::Client::new(), config.settings.clone()).await;
+
+    for domain in config.domains.iter() {
+        for entry in domain.entries.iter() {
+            runner.start(domain, entry).await;
+        }
+    }
+
+    if runner.settings.update_upon_start {
+        runner.send(0).await;
+    }
+
+    let mut events = watch_addresses(&runner.settings);
+
+    let (_watcher, mut reloads) = match reload::watch(path) {
+        Ok((watcher, reloads)) => (Some(watcher), Some(reloads)),
+        Err(e) => {
+            tracing::warn!("Unable to watch {} for changes with error {}. Send SIGHUP to reload it instead.", path.display(), e);
+            (None, None)
+        }
+    };
+
+    let mut hangups = match reload::hangups() {
+        Ok(v) => Some(v),
+        Err(e) => {
+            tracing::debug!("Unable to listen for SIGHUP with error {}", e);
+            None
+        }
+    };
+
+    let mut next_poll = Instant::now() + Duration::from_millis(runner.settings.ip_poll);
+
+    loop {
+        tokio::select! {
+            _ = time::sleep_until(next_poll) => {}
+            Some(_) = next_event(&mut events) => {
+                tracing::debug!("Network address change detected");
+
+                // Give new addresses time to settle, as they usually change in bursts
+                time::sleep(Duration::from_millis(ADDRESS_SETTLE_TIME)).await;
+
+                if let Some(events) = events.as_mut() {
+                    while events.try_recv().is_ok() {}
+                }
+            }
+            Some(_) = next_event(&mut reloads) => {
+                // Editors usually write the file in several steps
+                time::sleep(Duration::from_millis(RELOAD_SETTLE_TIME)).await;
+
+                if let Some(reloads) = reloads.as_mut() {
+                    while reloads.try_recv().is_ok() {}
+                }
+
+                tracing::info!("{} changed, reloading it", path.display());
+                reload_config(&mut runner, &mut events, path).await;
+
+                continue;
+            }
+            Some(_) = next_event(&mut hangups) => {
+                tracing::info!("Received SIGHUP, reloading {}", path.display());
+                reload_config(&mut runner, &mut events, path).await;
 
-    struct Channels<T>(Vec<Sender<T>>);
-    impl<T : Clone> Channels<T> {
-        async fn send(&self, value : &T) {
-            for v in self.0.iter() {
-                let _ = v.send(value.clone()).await;
+                continue;
             }
         }
+
+        runner.poll().await;
+
+        next_poll = Instant::now() + Duration::from_millis(runner.settings.ip_poll);
     }
+}
 
-    let mut addrs = Arc::new((public_ip::addr_v4().await, public_ip::addr_v6().await));
-    let mut channels = Vec::new();
+/// Brings every entry up to date once.
+async fn once(config : Config) -> ExitCode {
+    let mut runner = Runner::new(reqwest::Client::new(), config.settings.clone()).await;
+    let mut failed = false;
 
     for domain in config.domains.iter() {
-        let map : HashMap<String, String> = match get_dns_records(&client, &domain.zone_id, &domain.api_key).await {
-            Ok(v) => {
-                if v.response.success {
-                    HashMap::from_iter(v.result.into_iter().filter(|v| v.record_type == "A" || v.record_type == "AAAA").map(|v| (v.name, v.id)))
-                } else {
-                    tracing::warn!("Erroneous message received from Cloudflare API when querying IDs of entries for zone id {}: {}", domain.zone_id, v.response.errors[0]);
+        for entry in domain.entries.iter() {
+            let mut task = match runner.prepare(domain, entry).await {
+                Some(v) if v.found => v,
+                _ => {
+                    failed = true;
                     continue;
                 }
+            };
+
+            let sources = runner.sources(entry).clone();
+            let addrs = runner.addrs(&sources).await;
+
+            match task.update(&addrs).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    tracing::error!("Unable to update {} entry {} as no matching IP address was found.", entry.record_type, entry.name);
+                    failed = true;
+                }
+                Err(e) => {
+                    tracing::error!("Unable to update entry {} with error {}", entry.name, e);
+                    failed = true;
+                }
+            }
+        }
+    }
+
+    if failed { ExitCode::FAILURE } else { ExitCode::SUCCESS }
+}
+
+/// Prints what bringing every entry up to date would change, without changing anything.
+async fn dry_run(config : Config, format : Format) -> ExitCode {
+    let mut runner = Runner::new(reqwest::Client::new(), config.settings.clone()).await;
+    let mut plans = Vec::new();
+
+    for domain in config.domains.iter() {
+        let client = client_for(&runner.http, &runner.settings, domain);
+
+        for entry in domain.entries.iter() {
+            let sources = runner.sources(entry).clone();
+            let addrs = runner.addrs(&sources).await;
+
+            plans.push(plan::plan(&client, &domain.zone_id, entry, &addrs, runner.settings.per_page).await);
+        }
+    }
+
+    match format {
+        Format::Human => {
+            for plan in plans.iter() {
+                println!("{}", plan);
             }
+        }
+        Format::Json => match serde_json::to_string_pretty(&plans) {
+            Ok(v) => println!("{}", v),
             Err(e) => {
-                tracing::error!("Unable to reach Cloudflare API while querying IDs with error {}", e);
-                break;
+                tracing::error!("Unable to encode the plan with error {}", e);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    if plans.iter().any(|v| v.error.is_some()) { ExitCode::FAILURE } else { ExitCode::SUCCESS }
+}
+
+/// Validates the config and checks the API token of every domain against its zone.
+async fn check(config : Config) -> ExitCode {
+    if let Err(e) = config.validate() {
+        tracing::error!("{}", e);
+        return ExitCode::FAILURE;
+    }
+
+    let http = reqwest::Client::new();
+    let mut failed = false;
+
+    for domain in config.domains.iter() {
+        let client = client_for(&http, &config.settings, domain);
+
+        match client.verify_token().await {
+            Ok(v) if v.result.status == "active" => {}
+            Ok(v) => {
+                tracing::error!("The API token of zone {} is {}", domain.zone_id, v.result.status);
+                failed = true;
+                continue;
+            }
+            Err(e) => {
+                tracing::error!("Unable to verify the API token of zone {} with error {}", domain.zone_id, e);
+                failed = true;
+                continue;
+            }
+        }
+
+        match client.get_zone(&domain.zone_id).await {
+            Ok(v) => println!("{} ({}): {} entries, API token active", v.result.name, domain.zone_id, domain.entries.len()),
+            Err(e) => {
+                tracing::error!("Unable to access zone {} with error {}", domain.zone_id, e);
+                failed = true;
+            }
+        }
+    }
+
+    if failed { ExitCode::FAILURE } else { ExitCode::SUCCESS }
+}
+
+/// Prints the records of every zone in the config, or only of `zone` when given.
+async fn list(config : Config, zone : Option<&str>) -> ExitCode {
+    let http = reqwest::Client::new();
+    let mut listed : Vec<&str> = Vec::new();
+    let mut failed = false;
+
+    for domain in config.domains.iter() {
+        // Several domains may share a zone with different API tokens
+        if zone.is_some_and(|v| v != domain.zone_id) || listed.contains(&domain.zone_id.as_str()) {
+            continue;
+        }
+
+        let client = client_for(&http, &config.settings, domain);
+
+        let records = match client.list_dns_records(&domain.zone_id, &[], config.settings.per_page).await {
+            Ok(v) => v.result,
+            Err(e) => {
+                tracing::error!("Unable to list the records of zone {} with error {}", domain.zone_id, e);
+                failed = true;
+                continue;
             }
         };
 
-        for entry in domain.entries.iter() {
-            let id = match map.get(&entry.name) {
-                Some(v) => {
-                    v.clone()
-                }
-                None => {
-                    tracing::warn!("Unable to find ID of entry {} for zone id {}. Please make sure the entry name in the config matches the entry in Cloudflare EXACTLY.", entry.name, domain.zone_id);
-                    continue;
-                }
-            };
-            let (send, mut recv) = mpsc::channel::<Arc<(Option<Ipv4Addr>, Option<Ipv6Addr>)>>(1);
+        listed.push(&domain.zone_id);
 
-            let domain = domain.clone();
-            let entry = entry.clone();
-            let client = client.clone();
+        println!("# Zone {}", domain.zone_id);
 
-            channels.push(send);
+        for record in records {
+            let ttl = record.ttl.map_or_else(|| "-".to_owned(), |v| v.to_string());
+            let proxied = if record.proxied == Some(true) { "proxied" } else { "-" };
 
-            tokio::task::spawn(async move {
-                let ipv6 = if entry.record_type == "AAAA" {
-                    true
-                } else if entry.record_type == "A" {
-                    false
-                } else {
-                    tracing::warn!("Entry {} of zone {} has an improper record type ({:?}). Ignoring entry.", entry.name, domain.zone_id, entry.record_type);
-                    return;
-                };
-
-                let mut to_change : bool = false;
-                let mut update = DnsUpdate { entry, content: String::new() };
-                
-                while let Some(v) = recv.recv().await {
-                    if to_change {
-                        match do_update(&client, &domain.zone_id, &id, &domain.api_key, update.clone()).await {
-                            Ok(v) => {
-                                if v.success {
-                                    tracing::info!("Successfully changed the IP address of entry {} to {}.", update.entry.name, update.content);
-                                } else {
-                                    tracing::warn!("Erroneous message received from Cloudflare API: {}", v.errors[0]);
-                                }
-
-                                to_change = false;
-                            }
-                            Err(e) => {
-                                tracing::warn!("Unable to reach Cloudflare API with error {}", e);
-                            }
-                        }
+            println!("{}\t{}\t{}\t{}\t{}\t{}", record.id, record.record_type, record.name, ttl, proxied, record.content);
+        }
+    }
 
-                        continue;
-                    }
+    if failed { ExitCode::FAILURE } else { ExitCode::SUCCESS }
+}
 
-                    let addr = if ipv6 {
-                        match v.1 {
-                            Some(v) => {
-                                v.to_string()
-                            }
-                            None => {
-                                continue;
-                            }
-                        }
-                    } else {
-                        match v.0 {
-                            Some(v) => {
-                                v.to_string()
-                            }
-                            None => {
-                                continue;
-                            }
-                        }
-                    };
-
-                    if addr != update.content {
-                        update.content = addr;
-
-                        match do_update(&client, &domain.zone_id, &id, &domain.api_key, update.clone()).await {
-                            Ok(v) => {
-                                if v.success {
-                                    tracing::info!("Successfully changed the IP address of entry {} to {}.", update.entry.name, update.content);
-                                } else {
-                                    tracing::warn!("Erroneous message received from Cloudflare API: {}", v.errors[0]);
-                                }
-                            }
-                            Err(e) => {
-                                tracing::warn!("Unable to reach Cloudflare API with error {}", e);
-
-                                to_change = true;
-                            }
-                        }
+fn client_for(http : &reqwest::Client, settings : &Settings, domain : &DomainInfo) -> CloudflareClient {
+    CloudflareClient::new(http.clone(), domain.api_key.clone())
+        .with_base_url(settings.api_url.clone())
+        .with_retry(settings.retry)
+}
+
+/// Replaces the running config with the one at `path`, keeping the running config if the file cannot be
+/// read or is improper.
+async fn reload_config(runner : &mut Runner, events : &mut Option<Receiver<()>>, path : &Path) {
+    let config = match read_to_config(path).await.and_then(|v| v.validate().map(|_| v)) {
+        Ok(v) => v,
+        Err(e) => {
+            tracing::error!("Unable to reload {} with error {}. Keeping the running config.", path.display(), e);
+            return;
+        }
+    };
+
+    if config.settings.watch_addresses != runner.settings.watch_addresses {
+        *events = watch_addresses(&config.settings);
+    }
+
+    runner.apply(&config).await;
+}
+
+fn watch_addresses(settings : &Settings) -> Option<Receiver<()>> {
+    if !settings.watch_addresses {
+        return None;
+    }
 
+    match netlink::watch() {
+        Ok(v) => Some(v),
+        Err(e) => {
+            tracing::warn!("Unable to watch for address changes with error {}. Falling back to polling.", e);
+            None
+        }
+    }
+}
+
+async fn load_state(settings : &Settings) -> Option<Arc<StateFile>> {
+    let path = settings.state_file.as_ref()?;
+
+    Some(Arc::new(match StateFile::load(path).await {
+        Ok(v) => v,
+        Err(e) => {
+            tracing::warn!("Unable to load the state file {} with error {}. Starting with an empty state.", path.display(), e);
+            StateFile::new(path)
+        }
+    }))
+}
+
+/// The tasks keeping the records of every entry up to date
+struct Runner {
+    http : reqwest::Client,
+    settings : Settings,
+    state : Option<Arc<StateFile>>,
+    /// The addresses last detected by every set of IP sources in use, which are only queried once per poll
+    groups : Vec<(IpSources, Arc<Addrs>)>,
+    entries : Vec<RunningEntry>
+}
+
+/// An entry whose task stops once this is dropped
+struct RunningEntry {
+    zone_id : String,
+    api_key : String,
+    entry : Entry,
+    send : Sender<Arc<Addrs>>
+}
+
+impl Runner {
+    async fn new(http : reqwest::Client, settings : Settings) -> Self {
+        let state = load_state(&settings).await;
+
+        Self { http, settings, state, groups: Vec::new(), entries: Vec::new() }
+    }
+
+    /// Starts the task of `entry`, unless it is improper or its record cannot be found.
+    async fn start(&mut self, domain : &DomainInfo, entry : &Entry) {
+        let task = match self.prepare(domain, entry).await {
+            Some(v) => v,
+            None => return
+        };
+
+        let (send, recv) = mpsc::channel::<Arc<Addrs>>(1);
+
+        tokio::task::spawn(task.run(recv, self.settings.drift_check));
+
+        self.entries.push(RunningEntry { zone_id: domain.zone_id.clone(), api_key: domain.api_key.clone(), entry: entry.clone(), send });
+    }
+
+    /// Looks up the record of `entry` and what it is known to contain, unless the entry is improper or its
+    /// record cannot be found. The lookup is left to the task when it fails for a transient reason.
+    async fn prepare(&mut self, domain : &DomainInfo, entry : &Entry) -> Option<EntryTask> {
+        if let Err(e) = record::validate(entry) {
+            tracing::warn!("Entry {} of zone {} is improper: {}. Ignoring entry.", entry.name, domain.zone_id, e);
+            return None;
+        }
+
+        let client = client_for(&self.http, &self.settings, domain);
+
+        let sources = self.sources(entry).clone();
+        let addrs = self.addrs(&sources).await;
+
+        let mut task = EntryTask {
+            client,
+            zone_id: domain.zone_id.clone(),
+            update: DnsUpdate { entry: entry.clone(), content: String::new(), data: None, priority: None },
+            record: None,
+            found: false,
+            reconciled: false,
+            reported: None,
+            per_page: self.settings.per_page,
+            state: self.state.clone()
+        };
+
+        match task.find(&addrs).await {
+            Ok(true) => Some(task),
+            Ok(false) => None,
+            Err(e) if e.is_transient() => {
+                tracing::warn!("Unable to look up entry {} for zone id {} with error {}", entry.name, domain.zone_id, e);
+                Some(task)
+            }
+            Err(e) => {
+                tracing::error!("Unable to look up entry {} for zone id {} with error {}. Ignoring entry.", entry.name, domain.zone_id, e);
+                None
+            }
+        }
+    }
+
+    /// The addresses last detected by `sources`, detecting them if they are not in use yet.
+    async fn addrs(&mut self, sources : &IpSources) -> Arc<Addrs> {
+        if let Some((_, addrs)) = self.groups.iter().find(|(v, _)| v == sources) {
+            return addrs.clone();
+        }
+
+        let addrs = Arc::new(ip::detect(sources, &self.http).await);
+        self.groups.push((sources.clone(), addrs.clone()));
+
+        addrs
+    }
+
+    fn sources<'a>(&'a self, entry : &'a Entry) -> &'a IpSources {
+        entry.ip_sources.as_ref().unwrap_or(&self.settings.ip_sources)
+    }
+
+    /// Sends the last detected addresses to the entries starting at index `from`.
+    async fn send(&self, from : usize) {
+        for running in self.entries[from..].iter() {
+            let sources = self.sources(&running.entry);
+
+            if let Some((_, addrs)) = self.groups.iter().find(|(v, _)| v == sources) {
+                let _ = running.send.send(addrs.clone()).await;
+            }
+        }
+    }
+
+    /// Detects the addresses of every set of IP sources in use and sends them to their entries.
+    async fn poll(&mut self) {
+        let mut groups = Vec::new();
+
+        for running in self.entries.iter() {
+            let sources = self.sources(&running.entry);
+
+            if !groups.iter().any(|(v, _)| v == sources) {
+                groups.push((sources.clone(), Arc::new(ip::detect(sources, &self.http).await)));
+            }
+        }
+
+        self.groups = groups;
+        self.send(0).await;
+    }
+
+    /// Replaces the running config with `config`, stopping removed entries, starting added ones and
+    /// restarting the ones that changed.
+    async fn apply(&mut self, config : &Config) {
+        let old = std::mem::replace(&mut self.settings, config.settings.clone());
+
+        // These settings are captured by the task of every entry
+        let restart = old.api_url != config.settings.api_url
+            || old.retry != config.settings.retry
+            || old.per_page != config.settings.per_page
+            || old.drift_check != config.settings.drift_check
+            || old.state_file != config.settings.state_file;
+
+        if old.state_file != config.settings.state_file {
+            self.state = load_state(&config.settings).await;
+        }
+
+        self.entries.retain(|running| {
+            // Tasks which stopped as their record could not be found are started again
+            let keep = !restart && !running.send.is_closed() && config.domains.iter().any(|domain| {
+                domain.zone_id == running.zone_id && domain.api_key == running.api_key && domain.entries.contains(&running.entry)
+            });
+
+            if !keep {
+                tracing::info!("Stopping {} entry {} of zone {}", running.entry.record_type, running.entry.name, running.zone_id);
+            }
+
+            keep
+        });
+
+        let started = self.entries.len();
+
+        for domain in config.domains.iter() {
+            for entry in domain.entries.iter() {
+                let running = self.entries.iter().any(|v| v.zone_id == domain.zone_id && v.api_key == domain.api_key && &v.entry == entry);
+
+                if !running {
+                    tracing::info!("Starting {} entry {} of zone {}", entry.record_type, entry.name, domain.zone_id);
+                    self.start(domain, entry).await;
+                }
+            }
+        }
+
+        if self.settings.update_upon_start {
+            self.send(started).await;
+        }
+    }
+}
+
+/// Keeps the record of one entry up to date with the addresses it receives
+struct EntryTask {
+    client : CloudflareClient,
+    zone_id : String,
+    update : DnsUpdate,
+    record : Option<DnsRecord>,
+    /// Whether the record of the entry and what it is known to contain were looked up
+    found : bool,
+    reconciled : bool,
+    reported : Option<DnsRecord>,
+    per_page : u32,
+    state : Option<Arc<StateFile>>
+}
+
+impl EntryTask {
+    async fn run(mut self, mut recv : Receiver<Arc<Addrs>>, drift_check : Option<u64>) {
+        let mut to_change : bool = false;
+        let mut checks = drift_check.map(|v| {
+            let period = Duration::from_millis(v);
+            time::interval_at(Instant::now() + period, period)
+        });
+
+        loop {
+            let result = tokio::select! {
+                v = recv.recv() => match v {
+                    Some(v) if !self.found => match self.find(&v).await {
+                        Ok(true) => self.update(&v).await.map(|_| ()),
+                        Ok(false) => break,
+                        Err(e) if !e.is_transient() => {
+                            tracing::error!("Unable to look up entry {} for zone id {} with error {}. Ignoring entry until the config is reloaded.", self.update.entry.name, self.zone_id, e);
+                            break;
+                        }
+                        Err(e) => Err(e)
+                    },
+                    Some(_) if to_change => self.publish().await,
+                    Some(v) => self.update(&v).await.map(|_| ()),
+                    None => break
+                },
+                _ = next_tick(&mut checks) => {
+                    if to_change {
                         continue;
                     }
 
+                    self.check_drift().await
                 }
-            });
+            };
+
+            to_change = match result {
+                Ok(_) => false,
+                Err(e) if e.is_transient() => {
+                    tracing::warn!("Unable to update entry {} with error {}. Retrying on the next poll.", self.update.entry.name, e);
+                    true
+                }
+                Err(e) => {
+                    tracing::error!("Unable to update entry {} with error {}. Not retrying until the IP address changes.", self.update.entry.name, e);
+                    false
+                }
+            };
+        }
+    }
+
+    /// Looks up the record of a single entry, creating it if needed, and picks what the entry is known to
+    /// contain. Returns false when the record cannot be found or created.
+    async fn find(&mut self, addrs : &Addrs) -> Result<bool, UpdateError> {
+        let entry = self.update.entry.clone();
+        let state = self.state.as_deref();
+
+        // Record sets are looked up every time they are reconciled
+        if entry.mode == EntryMode::Single {
+            match find_record(&self.client, &self.zone_id, &entry, addrs, self.per_page, state).await? {
+                Some(v) => self.record = Some(v),
+                None => return Ok(false)
+            }
+        }
+
+        let known = match state {
+            Some(state) => state.get(&self.zone_id, &entry).await,
+            None => None
+        };
+
+        let seed = drift::seed(&entry, self.record.as_ref(), known);
+
+        if let (Some(state), Some(record), true) = (state, &self.record, seed.adopted) {
+            remember(state, &self.zone_id, &entry, Some(&record.id), &record.content, record.data.as_ref(), Some(&seed.update.content)).await;
+        }
+
+        let (update, reported) = (seed.update, seed.reported);
+
+        // Settings such as the TTL may have changed in the config since the record was last published
+        if let Some(record) = &self.record {
+            if let Err(e) = record::reconcile_fields(&self.client, &self.zone_id, record, &update).await {
+                tracing::warn!("Unable to update the settings of entry {} with error {}", entry.name, e);
+            }
+        }
+
+        self.update = update;
+        self.reported = reported;
+        // Record sets are reconciled once even when their content is known, as their other fields may have changed
+        self.reconciled = self.record.is_some();
+        self.found = true;
+
+        Ok(true)
+    }
+
+    /// Publishes the record for `addrs` if its content changed. Returns whether an address the entry needs
+    /// was detected.
+    async fn update(&mut self, addrs : &Addrs) -> Result<bool, UpdateError> {
+        let desired = match record::desired_update(&self.update.entry, addrs) {
+            Some(v) => v,
+            None => return Ok(false)
+        };
+
+        if !record::same_content(&self.update, &desired) || !self.reconciled {
+            self.update = desired;
+            self.reconciled = true;
+
+            self.publish().await?;
         }
+
+        Ok(true)
     }
 
-    let channels = Channels(channels);
+    /// Patches the record of the entry with the update, or reconciles its record set when it has no single record.
+    async fn publish(&mut self) -> Result<(), UpdateError> {
+        let update = &self.update;
+
+        let changed = match &self.record {
+            Some(record) => {
+                let patch = record::content_patch(record, update);
+                self.record = Some(self.client.patch_dns_record(&self.zone_id, &record.id, &patch).await?.result);
+                true
+            }
+            None => record::reconcile_set(&self.client, &self.zone_id, update, self.per_page).await?
+        };
+
+        if changed {
+            tracing::info!("Successfully changed the content of entry {} to {}.", update.entry.name, update.content);
+        } else {
+            tracing::debug!("Entry {} is already up to date.", update.entry.name);
+        }
 
-    if config.settings.update_upon_start {
-        channels.send(&addrs).await;
+        if let Some(state) = self.state.as_deref() {
+            remember(state, &self.zone_id, &update.entry, self.record.as_ref().map(|v| v.id.as_str()), &update.content, update.data.as_ref(), None).await;
+        }
+
+        Ok(())
     }
 
-    loop {
-        time::sleep(Duration::from_millis(config.settings.ip_poll)).await;
+    /// Rereads the record of the entry and handles changes made outside of the updater according to the drift
+    /// policy of the entry.
+    async fn check_drift(&mut self) -> Result<(), UpdateError> {
+        let id = match &self.record {
+            Some(v) => v.id.clone(),
+            None => return Ok(())
+        };
+
+        let record = match self.client.get_dns_record(&self.zone_id, &id).await {
+            Ok(v) => v.result,
+            Err(e) => {
+                tracing::warn!("Unable to reread entry {} for zone id {} with error {}", self.update.entry.name, self.zone_id, e);
+                return Ok(());
+            }
+        };
 
-        addrs = Arc::new((public_ip::addr_v4().await, public_ip::addr_v6().await));
+        // Restoring the record only patches what differs from it now
+        self.record = Some(record.clone());
+        let update = &self.update;
 
-        channels.send(&addrs).await;
+        match drift::check(&record, update, self.reported.as_ref()) {
+            Drift::Unchanged => {
+                self.reported = None;
+                Ok(())
+            }
+            Drift::Reported => Ok(()),
+            Drift::RestoreFields => record::reconcile_fields(&self.client, &self.zone_id, &record, update).await.map(|_| ()),
+            Drift::Restore => self.publish().await,
+            Drift::Warn => {
+                self.reported = Some(record);
+                Ok(())
+            }
+            Drift::Adopt => {
+                if let Some(state) = self.state.as_deref() {
+                    remember(state, &self.zone_id, &update.entry, Some(&record.id), &record.content, record.data.as_ref(), Some(&update.content)).await;
+                }
+
+                self.reported = Some(record);
+                Ok(())
+            }
+        }
     }
 }
 
-async fn read_to_config(path : &str) -> Result<Config, ConfigError> {
-    let mut file = File::open(path).await?;
-    let mut contents = String::new();
+/// Finds the record of `entry`, creating it if it is missing and `create_if_missing` is set. Returns `None`
+/// when the record does not exist and cannot be created.
+async fn find_record(client : &CloudflareClient, zone_id : &str, entry : &Entry, addrs : &Addrs, per_page : u32, state : Option<&StateFile>) -> Result<Option<DnsRecord>, UpdateError> {
+    let query = [("name", entry.name.as_str()), ("type", entry.record_type.as_str())];
+
+    let records = client.list_dns_records(zone_id, &query, per_page).await?.result;
+
+    if records.len() > 1 {
+        tracing::warn!("Found {} {} records named {} for zone id {}. Only the first one will be updated.", records.len(), entry.record_type, entry.name, zone_id);
+    }
+
+    let record = match records.into_iter().next() {
+        Some(v) => {
+            v
+        }
+        None if entry.create_if_missing => {
+            let create = match record::desired_update(entry, addrs) {
+                Some(v) => v,
+                None => {
+                    tracing::warn!("Unable to create {} entry {} for zone id {} as no matching IP address was found.", entry.record_type, entry.name, zone_id);
+                    return Ok(None);
+                }
+            };
+
+            let record = client.create_dns_record(zone_id, &create).await?.result;
+            tracing::info!("Created {} entry {} with content {}.", entry.record_type, entry.name, record.content);
+
+            if let Some(state) = state {
+                remember(state, zone_id, entry, Some(&record.id), &record.content, record.data.as_ref(), None).await;
+            }
 
-    file.read_to_string(&mut contents).await?;
+            record
+        }
+        None => {
+            tracing::warn!("Unable to find ID of {} entry {} for zone id {}. Please make sure the entry name in the config matches the entry in Cloudflare EXACTLY, or set create_if_missing.", entry.record_type, entry.name, zone_id);
+            return Ok(None);
+        }
+    };
 
-    Ok(toml::from_str(&contents)?)
+    Ok(Some(record))
 }
-async fn do_update(client : &reqwest::Client, zone_id : &str, id : &str, auth : &str, update : DnsUpdate) -> Result<Response, reqwest::Error> {
-    let v = client.put(format!("{}/zones/{}/dns_records/{}", ROOT, zone_id, id))
-        .json(&update)
-        .header("Authorization", format!("Bearer {}", auth))
-        .send()
-        .await?;
 
-    Ok(v.json().await.unwrap())
+async fn next_event(events : &mut Option<Receiver<()>>) -> Option<()> {
+    match events {
+        Some(v) => v.recv().await,
+        None => future::pending().await
+    }
 }
 
-async fn get_dns_records(client : &reqwest::Client, zone_id : &str, auth : &str) -> Result<ExtendedResponse<Vec<DnsRecord>>, reqwest::Error> {
-    let v = client.get(format!("{}/zones/{}/dns_records", ROOT, zone_id))
-        .header("Authorization", format!("Bearer {}", auth))
-        .send()
-        .await?;
+async fn next_tick(timer : &mut Option<Interval>) -> Instant {
+    match timer {
+        Some(v) => v.tick().await,
+        None => future::pending().await
+    }
+}
+
+async fn remember(state : &StateFile, zone_id : &str, entry : &Entry, id : Option<&str>, content : &str, data : Option<&Value>, desired : Option<&str>) {
+    if let Err(e) = state.set(zone_id, entry, id, content, data, desired).await {
+        tracing::warn!("Unable to save the state of entry {} to {} with error {}", entry.name, state.path().display(), e);
+    }
+}
 
-    Ok(v.json().await.unwrap())
-}
\ No newline at end of file
+/// Reads the config at `path` with the `CFDDNS_` environment variables applied on top of it. The file may be
+/// missing when the config is given through environment variables only.
+async fn read_to_config(path : &Path) -> Result<Config, ConfigError> {
+    let vars = environment::vars();
+    let mut contents = String::new();
+
+    match File::open(path).await {
+        Ok(mut file) => {
+            file.read_to_string(&mut contents).await?;
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound && !vars.is_empty() => {}
+        Err(e) => return Err(e.into())
+    }
+
+    let mut value : toml::Value = toml::from_str(&contents)?;
+    environment::overlay(&mut value, &vars)?;
+
+    let mut config : Config = value.try_into()?;
+    config.resolve_secrets()?;
+
+    Ok(config)
+}
@@ -0,0 +1,51 @@
+use std::io;
+
+use tokio::sync::mpsc::Receiver;
+
+/// Subscribes to address change notifications of every network interface. A message is received
+/// whenever an IPv4 or IPv6 address is added or removed, with bursts of changes coalesced.
+#[cfg(target_os = "linux")]
+pub fn watch() -> io::Result<Receiver<()>> {
+    use std::os::fd::AsRawFd;
+    use nix::sys::socket::{self, AddressFamily, MsgFlags, NetlinkAddr, SockFlag, SockProtocol, SockType};
+    use tokio::sync::mpsc;
+
+    /// `RTMGRP_IPV4_IFADDR | RTMGRP_IPV6_IFADDR`
+    const GROUPS : u32 = 0x10 | 0x100;
+
+    let fd = socket::socket(AddressFamily::Netlink, SockType::Raw, SockFlag::SOCK_CLOEXEC, SockProtocol::NetlinkRoute)?;
+    socket::bind(fd.as_raw_fd(), &NetlinkAddr::new(0, GROUPS))?;
+
+    let (send, recv) = mpsc::channel(1);
+
+    std::thread::spawn(move || {
+        let mut buf = vec![0u8; 8192];
+
+        loop {
+            match socket::recv(fd.as_raw_fd(), &mut buf, MsgFlags::empty()) {
+                Ok(_) => {
+                    // A notification is already pending when the channel is full
+                    if let Err(mpsc::error::TrySendError::Closed(_)) = send.try_send(()) {
+                        return;
+                    }
+                }
+                Err(nix::errno::Errno::EINTR) => {}
+                // ENOBUFS means notifications were dropped, which still means something changed
+                Err(nix::errno::Errno::ENOBUFS) => {
+                    let _ = send.try_send(());
+                }
+                Err(e) => {
+                    tracing::error!("Stopped watching for address changes with error {}", e);
+                    return;
+                }
+            }
+        }
+    });
+
+    Ok(recv)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn watch() -> io::Result<Receiver<()>> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "address change notifications are only supported on Linux"))
+}
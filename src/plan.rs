@@ -0,0 +1,145 @@
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::{client::CloudflareClient, data::{Entry, EntryMode, RecordType}, ip::Addrs, record};
+
+/// What publishing an entry would change, found without changing anything
+#[derive(Serialize, Clone, Debug)]
+pub struct EntryPlan {
+    pub zone_id : String,
+    pub name : String,
+    #[serde(rename = "type")]
+    pub record_type : RecordType,
+    pub action : Action,
+    /// The content of the records of the entry, which are several for record sets
+    pub current : Vec<String>,
+    /// The content the entry should have for the detected addresses
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub desired : Option<String>,
+    pub content_changed : bool,
+    /// The other fields which would change, among `ttl`, `proxied`, `tags` and `comment`
+    pub fields : Vec<String>,
+    /// The content of the owned records of a record set which would be deleted
+    pub removed : Vec<String>,
+    /// Why the entry cannot be published
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error : Option<String>
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Unchanged,
+    Update,
+    Create,
+    Skip
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Unchanged => "unchanged",
+            Self::Update => "update",
+            Self::Create => "create",
+            Self::Skip => "skip"
+        })
+    }
+}
+
+/// Finds what publishing `entry` for `addrs` would change, only reading the records of the zone.
+pub async fn plan(client : &CloudflareClient, zone_id : &str, entry : &Entry, addrs : &Addrs, per_page : u32) -> EntryPlan {
+    let mut plan = EntryPlan {
+        zone_id: zone_id.to_owned(),
+        name: entry.name.clone(),
+        record_type: entry.record_type,
+        action: Action::Skip,
+        current: Vec::new(),
+        desired: None,
+        content_changed: false,
+        fields: Vec::new(),
+        removed: Vec::new(),
+        error: None
+    };
+
+    if let Err(e) = record::validate(entry) {
+        plan.error = Some(e.to_string());
+        return plan;
+    }
+
+    let query = [("name", entry.name.as_str()), ("type", entry.record_type.as_str())];
+
+    let records = match client.list_dns_records(zone_id, &query, per_page).await {
+        Ok(v) => v.result,
+        Err(e) => {
+            plan.error = Some(e.to_string());
+            return plan;
+        }
+    };
+
+    let records = record::candidates(records, entry);
+
+    plan.current = records.iter().map(|v| v.content.clone()).collect();
+
+    let update = match record::desired_update(entry, addrs) {
+        Some(v) => v,
+        None => {
+            plan.error = Some("no matching IP address was detected".to_owned());
+            return plan;
+        }
+    };
+
+    plan.desired = Some(update.content.clone());
+
+    // The same records are kept and deleted as when the entry is published
+    let selection = record::select(records, &update);
+
+    plan.removed = selection.removed.into_iter().map(|v| v.content).collect();
+    plan.content_changed = !selection.current;
+
+    plan.action = match selection.kept {
+        Some(record) => {
+            plan.fields = record::changed_fields(&record, &update).keys().cloned().collect();
+
+            if plan.content_changed || !plan.fields.is_empty() || !plan.removed.is_empty() {
+                Action::Update
+            } else {
+                Action::Unchanged
+            }
+        }
+        None if entry.mode == EntryMode::Set || entry.create_if_missing => Action::Create,
+        None => {
+            plan.error = Some("the record does not exist and create_if_missing is not set".to_owned());
+            Action::Skip
+        }
+    };
+
+    plan
+}
+
+impl fmt::Display for EntryPlan {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {} (zone {})", self.action, self.record_type, self.name, self.zone_id)?;
+
+        if let Some(e) = &self.error {
+            return write!(f, ": {}", e);
+        }
+
+        let current = if self.current.is_empty() { "(none)".to_owned() } else { self.current.join(", ") };
+
+        match &self.desired {
+            Some(desired) if self.content_changed => write!(f, "\n    content: {} -> {}", current, desired)?,
+            _ => write!(f, "\n    content: {}", current)?
+        }
+
+        if !self.fields.is_empty() {
+            write!(f, "\n    fields: {}", self.fields.join(", "))?;
+        }
+
+        if !self.removed.is_empty() {
+            write!(f, "\n    removes: {}", self.removed.join(", "))?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,529 @@
+use std::net::IpAddr;
+
+use serde_json::{json, Map, Value};
+
+use crate::{client::CloudflareClient, data::{DnsRecord, DnsUpdate, DriftPolicy, Entry, EntryMode, OwnerMarker, RecordType}, error::{EntryError, UpdateError}, ip::{self, Addrs}, template};
+
+/// Checks that `entry` has every field its record type requires, and none it cannot use.
+pub fn validate(entry : &Entry) -> Result<(), EntryError> {
+    let kind = entry.record_type;
+
+    if entry.prefix_length > 128 {
+        return Err(EntryError::PrefixLength(entry.prefix_length));
+    }
+
+    if let Some(content) = &entry.content {
+        template::validate(content)?;
+    }
+
+    if let Some(sources) = &entry.ip_sources {
+        if let Some(quorum) = sources.invalid_quorum() {
+            return Err(EntryError::InvalidQuorum { quorum, providers: sources.providers.len() });
+        }
+    }
+
+    let (content, target) = match kind {
+        RecordType::A | RecordType::Aaaa => (Field::Optional, Field::Unsupported),
+        RecordType::Txt | RecordType::Cname => (Field::Required, Field::Unsupported),
+        RecordType::Https | RecordType::Svcb => (Field::Unsupported, Field::Optional),
+        RecordType::Uri | RecordType::Srv => (Field::Unsupported, Field::Required)
+    };
+
+    content.check(kind, "content", entry.content.is_some())?;
+    target.check(kind, "target", entry.target.is_some())?;
+
+    let data = matches!(kind, RecordType::Https | RecordType::Svcb | RecordType::Uri | RecordType::Srv);
+    let weighted = matches!(kind, RecordType::Uri | RecordType::Srv);
+    let svcb = matches!(kind, RecordType::Https | RecordType::Svcb);
+
+    if !data && entry.priority.is_some() {
+        return Err(EntryError::UnsupportedField(kind, "priority"));
+    }
+    if !weighted && entry.weight.is_some() {
+        return Err(EntryError::UnsupportedField(kind, "weight"));
+    }
+    if !svcb && entry.svc_params.is_some() {
+        return Err(EntryError::UnsupportedField(kind, "svc_params"));
+    }
+
+    let port = if kind == RecordType::Srv { Field::Required } else { Field::Unsupported };
+    port.check(kind, "port", entry.port.is_some())?;
+
+    let owner = if entry.mode == EntryMode::Set { Field::Required } else { Field::Unsupported };
+    owner.check(kind, "owner", entry.owner.is_some())?;
+
+    if entry.mode == EntryMode::Set && entry.owner_marker == OwnerMarker::Comment && entry.comment.is_some() {
+        return Err(EntryError::UnsupportedField(kind, "comment with a comment owner marker"));
+    }
+
+    // Record sets are reconciled as a whole every time they are published
+    if entry.mode == EntryMode::Set && entry.drift != DriftPolicy::Correct {
+        return Err(EntryError::UnsupportedField(kind, "drift in set mode"));
+    }
+
+    Ok(())
+}
+
+enum Field {
+    Required,
+    Optional,
+    Unsupported
+}
+
+impl Field {
+    fn check(&self, kind : RecordType, name : &'static str, present : bool) -> Result<(), EntryError> {
+        match (self, present) {
+            (Self::Required, false) => Err(EntryError::MissingField(kind, name)),
+            (Self::Unsupported, true) => Err(EntryError::UnsupportedField(kind, name)),
+            _ => Ok(())
+        }
+    }
+}
+
+/// Whether the live `record` still has the content of `update`. The structured data of HTTPS, SVCB, URI
+/// and SRV records is compared instead of their content, which Cloudflare formats on its own.
+pub fn is_current(record : &DnsRecord, update : &DnsUpdate) -> bool {
+    has_content(&record.content, record.data.as_ref(), update)
+}
+
+/// Whether `current`, such as the update last published, already has the content of `desired`, compared
+/// like [`is_current`].
+pub fn same_content(current : &DnsUpdate, desired : &DnsUpdate) -> bool {
+    has_content(&current.content, current.data.as_ref(), desired)
+}
+
+fn has_content(content : &str, data : Option<&Value>, update : &DnsUpdate) -> bool {
+    if let (Some(Value::Object(desired)), Some(Value::Object(live))) = (&update.data, data) {
+        return desired.iter().all(|(k, v)| live.get(k).is_some_and(|live| same_value(v, live)));
+    }
+
+    // Addresses are compared parsed, as IPv6 addresses can be written in several ways
+    match (content.parse::<IpAddr>(), update.content.parse::<IpAddr>()) {
+        (Ok(live), Ok(desired)) => live == desired,
+        _ => content == update.content
+    }
+}
+
+/// The fields of `update` other than its content which differ from the live `record`, as the body of a
+/// PATCH request. Fields the entry does not set are left as they are.
+pub fn changed_fields(record : &DnsRecord, update : &DnsUpdate) -> Map<String, Value> {
+    let entry = &update.entry;
+    let mut fields = Map::new();
+
+    // Cloudflare always reports an automatic TTL for proxied records
+    let proxied = entry.proxied.or(record.proxied).unwrap_or(false);
+
+    if !proxied && record.ttl != Some(entry.ttl) {
+        fields.insert("ttl".to_owned(), json!(entry.ttl));
+    }
+
+    if let Some(proxied) = entry.proxied {
+        if record.proxied != Some(proxied) {
+            fields.insert("proxied".to_owned(), json!(proxied));
+        }
+    }
+
+    if let Some(tags) = desired_tags(entry, &record.tags) {
+        let mut desired = tags.clone();
+        let mut live = record.tags.clone();
+        desired.sort();
+        live.sort();
+
+        if desired != live {
+            fields.insert("tags".to_owned(), json!(tags));
+        }
+    }
+
+    if let Some(comment) = &entry.comment {
+        if record.comment.as_deref().unwrap_or("") != comment {
+            fields.insert("comment".to_owned(), json!(comment));
+        }
+    }
+
+    fields
+}
+
+/// The tags `entry` gives a record which has the tags `live`: the tags of the entry, or else the live tags,
+/// along with the owner marker of record sets. `None` when the tags are left as they are.
+fn desired_tags(entry : &Entry, live : &[String]) -> Option<Vec<String>> {
+    let marker = owner_marker(entry).filter(|_| entry.owner_marker == OwnerMarker::Tag);
+
+    let mut tags = match (&entry.tags, &marker) {
+        (Some(tags), _) => tags.clone(),
+        (None, Some(_)) => live.to_vec(),
+        (None, None) => return None
+    };
+
+    if let Some(marker) = marker.filter(|v| !tags.contains(v)) {
+        tags.push(marker);
+    }
+
+    Some(tags)
+}
+
+/// The body of a PATCH request giving the live `record` the content of `update` along with its other fields
+/// which differ. Unlike a PUT, fields the entry does not set are kept as they are.
+pub fn content_patch(record : &DnsRecord, update : &DnsUpdate) -> Map<String, Value> {
+    let mut patch = changed_fields(record, update);
+
+    match &update.data {
+        Some(data) => patch.insert("data".to_owned(), data.clone()),
+        None => patch.insert("content".to_owned(), json!(update.content))
+    };
+
+    if let Some(priority) = update.priority {
+        patch.insert("priority".to_owned(), json!(priority));
+    }
+
+    patch
+}
+
+/// Patches the fields of `record` other than its content which differ from `update`. Returns whether
+/// the record was changed.
+pub async fn reconcile_fields(client : &CloudflareClient, zone_id : &str, record : &DnsRecord, update : &DnsUpdate) -> Result<bool, UpdateError> {
+    let fields = changed_fields(record, update);
+
+    if fields.is_empty() {
+        return Ok(false);
+    }
+
+    let names = fields.keys().cloned().collect::<Vec<_>>().join(", ");
+
+    client.patch_dns_record(zone_id, &record.id, &fields).await?;
+    tracing::info!("Changed {} of record {} of entry {}.", names, record.id, update.entry.name);
+
+    Ok(true)
+}
+
+/// Compares JSON values, treating numbers and strings with the same text as equal.
+fn same_value(a : &Value, b : &Value) -> bool {
+    match (a, b) {
+        (Value::Number(a), Value::String(b)) | (Value::String(b), Value::Number(a)) => a.to_string() == *b,
+        _ => a == b
+    }
+}
+
+/// Builds the record `entry` should have for the detected addresses, or `None` when an address it
+/// needs was not detected. `entry` must have passed [`validate`].
+pub fn desired_update(entry : &Entry, addrs : &Addrs) -> Option<DnsUpdate> {
+    let ipv4 = addrs.0;
+    let ipv6 = addrs.1.map(|v| match entry.ipv6_suffix {
+        Some(suffix) => ip::with_suffix(v, suffix, entry.prefix_length),
+        None => v
+    });
+
+    let mut update = DnsUpdate { entry: entry.clone(), content: String::new(), data: None, priority: None };
+
+    // Owner tags are merged into the tags of the record, see `desired_tags`
+    if let (Some(marker), OwnerMarker::Comment) = (owner_marker(entry), entry.owner_marker) {
+        update.entry.comment = Some(marker);
+    }
+
+    match entry.record_type {
+        RecordType::A | RecordType::Aaaa | RecordType::Txt | RecordType::Cname => {
+            update.content = match (&entry.content, entry.record_type) {
+                (Some(template), _) => template::render(template, ipv4, ipv6).ok()??,
+                (None, RecordType::A) => ipv4?.to_string(),
+                (None, RecordType::Aaaa) => ipv6?.to_string(),
+                (None, _) => return None
+            };
+        }
+        RecordType::Https | RecordType::Svcb => {
+            let priority = entry.priority.unwrap_or(1);
+            let target = entry.target.as_deref().unwrap_or(".");
+            let mut value = entry.svc_params.clone().unwrap_or_default();
+
+            if let Some(v) = ipv4.filter(|_| !value.contains("ipv4hint")) {
+                value.push_str(&format!(" ipv4hint={}", v));
+            }
+            if let Some(v) = ipv6.filter(|_| !value.contains("ipv6hint")) {
+                value.push_str(&format!(" ipv6hint={}", v));
+            }
+
+            let value = value.trim();
+
+            update.content = format!("{} {} {}", priority, target, value).trim_end().to_owned();
+            update.data = Some(json!({ "priority": priority, "target": target, "value": value }));
+        }
+        RecordType::Uri => {
+            let priority = entry.priority.unwrap_or(0);
+            let weight = entry.weight.unwrap_or(0);
+            let target = entry.target.as_deref()?;
+
+            update.content = format!("{} \"{}\"", weight, target);
+            update.data = Some(json!({ "weight": weight, "target": target }));
+            update.priority = Some(priority);
+        }
+        RecordType::Srv => {
+            let priority = entry.priority.unwrap_or(0);
+            let weight = entry.weight.unwrap_or(0);
+            let port = entry.port?;
+            let target = entry.target.as_deref()?;
+
+            update.content = format!("{} {} {}", weight, port, target);
+            update.data = Some(json!({ "priority": priority, "weight": weight, "port": port, "target": target }));
+        }
+    }
+
+    Some(update)
+}
+
+/// The marker of the records owned by `entry` in `set` mode.
+pub fn owner_marker(entry : &Entry) -> Option<String> {
+    match (entry.mode, &entry.owner) {
+        (EntryMode::Set, Some(owner)) => Some(format!("cloudflareddns:{}", owner)),
+        _ => None
+    }
+}
+
+/// Whether `record` is marked as owned by `entry`.
+pub fn is_owned(record : &DnsRecord, entry : &Entry) -> bool {
+    match owner_marker(entry) {
+        Some(marker) => match entry.owner_marker {
+            OwnerMarker::Tag => record.tags.contains(&marker),
+            OwnerMarker::Comment => record.comment.as_deref() == Some(marker.as_str())
+        },
+        None => false
+    }
+}
+
+/// The records publishing an entry may change: the first listed record for single entries, and the owned
+/// records for record sets.
+pub fn candidates(records : Vec<DnsRecord>, entry : &Entry) -> Vec<DnsRecord> {
+    match entry.mode {
+        EntryMode::Single => records.into_iter().take(1).collect(),
+        EntryMode::Set => records.into_iter().filter(|v| is_owned(v, entry)).collect()
+    }
+}
+
+/// Which of the candidates of an entry publishing `update` keeps and deletes
+#[derive(Debug, PartialEq)]
+pub struct Selection {
+    /// The record given the content of `update`, unless one has to be created
+    pub kept : Option<DnsRecord>,
+    /// Whether the kept record already has the content of `update`
+    pub current : bool,
+    /// The other candidates, which are deleted
+    pub removed : Vec<DnsRecord>
+}
+
+/// Keeps a candidate which already has the content of `update`, or else the first candidate, and deletes
+/// the others.
+pub fn select(mut candidates : Vec<DnsRecord>, update : &DnsUpdate) -> Selection {
+    let keep = candidates.iter().position(|v| is_current(v, update));
+    let first = if candidates.is_empty() { None } else { Some(0) };
+    let kept = keep.or(first).map(|i| candidates.remove(i));
+
+    Selection { kept, current: keep.is_some(), removed: candidates }
+}
+
+/// Makes the records owned by the entry of `update` consist of exactly one record matching `update`,
+/// reusing an owned record where possible and deleting the others. Records of other owners are left untouched.
+/// Returns whether any record was changed.
+pub async fn reconcile_set(client : &CloudflareClient, zone_id : &str, update : &DnsUpdate, per_page : u32) -> Result<bool, UpdateError> {
+    let entry = &update.entry;
+    let query = [("name", entry.name.as_str()), ("type", entry.record_type.as_str())];
+
+    let records = client.list_dns_records(zone_id, &query, per_page).await?.result;
+    let selection = select(candidates(records, entry), update);
+
+    let mut changed = !selection.removed.is_empty();
+
+    match selection.kept {
+        Some(record) if selection.current => {
+            tracing::debug!("Record {} of entry {} already has content {}.", record.id, entry.name, update.content);
+            changed |= reconcile_fields(client, zone_id, &record, update).await?;
+        }
+        Some(record) => {
+            client.patch_dns_record(zone_id, &record.id, &content_patch(&record, update)).await?;
+            tracing::info!("Changed record {} of entry {} from {} to {}.", record.id, entry.name, record.content, update.content);
+            changed = true;
+        }
+        None => {
+            let mut create = update.clone();
+            create.entry.tags = desired_tags(entry, &[]);
+
+            let record = client.create_dns_record(zone_id, &create).await?.result;
+            tracing::info!("Created record {} of entry {} with content {}.", record.id, entry.name, record.content);
+            changed = true;
+        }
+    }
+
+    for record in selection.removed {
+        client.delete_dns_record(zone_id, &record.id).await?;
+        tracing::info!("Deleted record {} of entry {} with content {}.", record.id, entry.name, record.content);
+    }
+
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn record(id : &str, content : &str, tags : &[&str]) -> DnsRecord {
+        DnsRecord {
+            content: content.to_owned(),
+            id: id.to_owned(),
+            name: "pool.example.com".to_owned(),
+            record_type: "A".to_owned(),
+            ttl: Some(1),
+            proxied: Some(false),
+            tags: tags.iter().map(|v| v.to_string()).collect(),
+            comment: None,
+            data: None
+        }
+    }
+
+    fn update() -> DnsUpdate {
+        let entry : Entry = toml::from_str(r#"
+            name = "pool.example.com"
+            mode = "set"
+            owner = "host-a"
+        "#).unwrap();
+
+        desired_update(&entry, &(Some(Ipv4Addr::new(203, 0, 113, 7)), None)).unwrap()
+    }
+
+    /// The update of a single entry with the given settings
+    fn single(settings : &str) -> DnsUpdate {
+        let entry : Entry = toml::from_str(&format!("name = \"pool.example.com\"\n{}", settings)).unwrap();
+
+        desired_update(&entry, &(Some(Ipv4Addr::new(203, 0, 113, 7)), None)).unwrap()
+    }
+
+    fn names(fields : &Map<String, Value>) -> Vec<&str> {
+        fields.keys().map(String::as_str).collect()
+    }
+
+    fn ids(records : &[DnsRecord]) -> Vec<&str> {
+        records.iter().map(|v| v.id.as_str()).collect()
+    }
+
+    #[test]
+    fn keeps_current_owned_record() {
+        let update = update();
+        let records = vec![
+            record("other-current", "203.0.113.7", &["cloudflareddns:host-b"]),
+            record("stale-1", "198.51.100.1", &["cloudflareddns:host-a"]),
+            record("unowned", "203.0.113.7", &[]),
+            record("current", "203.0.113.7", &["home", "cloudflareddns:host-a"]),
+            record("stale-2", "198.51.100.2", &["cloudflareddns:host-a"]),
+            record("other-stale", "198.51.100.3", &["cloudflareddns:host-b"])
+        ];
+
+        let selection = select(candidates(records, &update.entry), &update);
+
+        assert_eq!(selection.kept.as_ref().map(|v| v.id.as_str()), Some("current"));
+        assert!(selection.current);
+        assert_eq!(ids(&selection.removed), ["stale-1", "stale-2"]);
+    }
+
+    #[test]
+    fn keeps_other_tags_of_owned_records() {
+        let update = update();
+
+        let current = record("current", "203.0.113.7", &["home", "cloudflareddns:host-a"]);
+        assert!(changed_fields(&current, &update).is_empty());
+
+        // Only the marker is added to records which lost it
+        let unmarked = record("unmarked", "203.0.113.7", &["home"]);
+        assert_eq!(changed_fields(&unmarked, &update).get("tags"), Some(&json!(["home", "cloudflareddns:host-a"])));
+
+        // Tags set on the entry replace the others
+        let mut tagged = update.clone();
+        tagged.entry.tags = Some(vec!["office".to_owned()]);
+        assert_eq!(changed_fields(&current, &tagged).get("tags"), Some(&json!(["office", "cloudflareddns:host-a"])));
+    }
+
+    #[test]
+    fn reuses_first_stale_owned_record() {
+        let update = update();
+        let records = vec![
+            record("other-current", "203.0.113.7", &["cloudflareddns:host-b"]),
+            record("stale-1", "198.51.100.1", &["cloudflareddns:host-a"]),
+            record("stale-2", "198.51.100.2", &["cloudflareddns:host-a"])
+        ];
+
+        let selection = select(candidates(records, &update.entry), &update);
+
+        assert_eq!(selection.kept.as_ref().map(|v| v.id.as_str()), Some("stale-1"));
+        assert!(!selection.current);
+        assert_eq!(ids(&selection.removed), ["stale-2"]);
+    }
+
+    #[test]
+    fn creates_without_owned_records() {
+        let update = update();
+        let records = vec![
+            record("other-current", "203.0.113.7", &["cloudflareddns:host-b"]),
+            record("unowned", "203.0.113.7", &[])
+        ];
+
+        let selection = select(candidates(records, &update.entry), &update);
+
+        assert_eq!(selection, Selection { kept: None, current: false, removed: Vec::new() });
+    }
+
+    #[test]
+    fn leaves_unset_fields_alone() {
+        let mut live = record("r1", "203.0.113.7", &["home"]);
+        live.proxied = Some(true);
+        live.comment = Some("set in the dashboard".to_owned());
+
+        assert!(changed_fields(&live, &single("")).is_empty());
+    }
+
+    #[test]
+    fn changes_set_fields() {
+        let live = record("r1", "203.0.113.7", &["home"]);
+        let update = single("ttl = 300\nproxied = true\ntags = [\"office\"]\ncomment = \"updater\"");
+        let fields = changed_fields(&live, &update);
+
+        // The TTL of proxied records is automatic
+        assert_eq!(names(&fields), ["comment", "proxied", "tags"]);
+        assert_eq!(fields["proxied"], json!(true));
+        assert_eq!(fields["tags"], json!(["office"]));
+        assert_eq!(fields["comment"], json!("updater"));
+    }
+
+    #[test]
+    fn skips_ttl_of_proxied_records() {
+        let mut live = record("r1", "203.0.113.7", &[]);
+        assert_eq!(names(&changed_fields(&live, &single("ttl = 300"))), ["ttl"]);
+
+        // Proxied on Cloudflare without the entry setting it
+        live.proxied = Some(true);
+        assert!(changed_fields(&live, &single("ttl = 300")).is_empty());
+
+        // No longer proxied once the entry is published
+        assert_eq!(names(&changed_fields(&live, &single("ttl = 300\nproxied = false"))), ["proxied", "ttl"]);
+    }
+
+    #[test]
+    fn compares_tags_in_any_order() {
+        let live = record("r1", "203.0.113.7", &["b", "a"]);
+
+        assert!(changed_fields(&live, &single("tags = [\"a\", \"b\"]")).is_empty());
+        assert_eq!(names(&changed_fields(&live, &single("tags = [\"a\"]"))), ["tags"]);
+        assert_eq!(names(&changed_fields(&live, &single("tags = []"))), ["tags"]);
+    }
+
+    #[test]
+    fn treats_missing_comment_as_empty() {
+        let live = record("r1", "203.0.113.7", &[]);
+
+        assert!(changed_fields(&live, &single("comment = \"\"")).is_empty());
+        assert_eq!(names(&changed_fields(&live, &single("comment = \"updater\""))), ["comment"]);
+    }
+
+    #[test]
+    fn patches_content_with_changed_fields() {
+        let live = record("r1", "198.51.100.1", &["home"]);
+        let patch = content_patch(&live, &single("proxied = false"));
+
+        assert_eq!(Value::Object(patch), json!({ "content": "203.0.113.7" }));
+    }
+}
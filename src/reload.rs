@@ -0,0 +1,58 @@
+use std::{io, path::Path};
+
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::mpsc::{self, Receiver};
+
+/// Watches the file at `path` for changes. A message is received whenever the file is written, created,
+/// replaced or removed, with bursts of changes coalesced. Changes are only watched for as long as the
+/// returned watcher is kept.
+pub fn watch(path : &Path) -> notify::Result<(RecommendedWatcher, Receiver<()>)> {
+    let (send, recv) = mpsc::channel(1);
+    let name = path.file_name().map(|v| v.to_owned());
+
+    // Editors often replace the file instead of writing to it, which is only seen from its directory
+    let dir = match path.parent() {
+        Some(v) if !v.as_os_str().is_empty() => v,
+        _ => Path::new(".")
+    };
+
+    let mut watcher = notify::recommended_watcher(move |event : notify::Result<Event>| {
+        match event {
+            Ok(event) if !event.kind.is_access() && event.paths.iter().any(|v| v.file_name() == name.as_deref()) => {
+                // A notification is already pending when the channel is full
+                let _ = send.try_send(());
+            }
+            Ok(_) => {}
+            Err(e) => tracing::warn!("Error while watching the config file: {}", e)
+        }
+    })?;
+
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+
+    Ok((watcher, recv))
+}
+
+/// Subscribes to SIGHUP, which asks the daemon to reload its config. Must be called from within the
+/// Tokio runtime.
+#[cfg(unix)]
+pub fn hangups() -> io::Result<Receiver<()>> {
+    use tokio::signal::unix::{self, SignalKind};
+
+    let mut signals = unix::signal(SignalKind::hangup())?;
+    let (send, recv) = mpsc::channel(1);
+
+    tokio::spawn(async move {
+        while signals.recv().await.is_some() {
+            if let Err(mpsc::error::TrySendError::Closed(_)) = send.try_send(()) {
+                return;
+            }
+        }
+    });
+
+    Ok(recv)
+}
+
+#[cfg(not(unix))]
+pub fn hangups() -> io::Result<Receiver<()>> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "SIGHUP is only supported on Unix"))
+}
@@ -0,0 +1,94 @@
+use std::{env, fs, path::Path};
+
+use crate::error::SecretError;
+
+/// Resolves a secret given in the config, which is either the secret itself or a reference to it:
+/// - `env:NAME`, the value of the environment variable `NAME`
+/// - `file:PATH`, the contents of the file at `PATH`
+/// - `credential:NAME`, the systemd credential `NAME` in `$CREDENTIALS_DIRECTORY`
+///
+/// Surrounding whitespace is removed from secrets read from files, as they usually end with a newline.
+pub fn resolve(value : &str) -> Result<String, SecretError> {
+    let secret = if let Some(name) = value.strip_prefix("env:") {
+        env::var(name).map_err(|_| SecretError::MissingVariable(name.to_owned()))?
+    } else if let Some(path) = value.strip_prefix("file:") {
+        read(Path::new(path))?
+    } else if let Some(name) = value.strip_prefix("credential:") {
+        let dir = env::var_os("CREDENTIALS_DIRECTORY").ok_or(SecretError::NoCredentialsDirectory)?;
+
+        read(&Path::new(&dir).join(name))?
+    } else {
+        return Ok(value.to_owned());
+    };
+
+    if secret.is_empty() {
+        return Err(SecretError::Empty);
+    }
+
+    Ok(secret)
+}
+
+fn read(path : &Path) -> Result<String, SecretError> {
+    Ok(fs::read_to_string(path)?.trim().to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    /// A directory only used by the test called `name`
+    fn dir(name : &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("cloudflareddns-secret-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn plain_values_are_kept() {
+        assert_eq!(resolve("abc123").unwrap(), "abc123");
+        assert_eq!(resolve(" abc123 ").unwrap(), " abc123 ");
+        assert_eq!(resolve("").unwrap(), "");
+    }
+
+    #[test]
+    fn reads_environment_variables() {
+        env::set_var("CLOUDFLAREDDNS_TEST_SECRET", "abc123");
+        env::set_var("CLOUDFLAREDDNS_TEST_EMPTY", "");
+
+        assert_eq!(resolve("env:CLOUDFLAREDDNS_TEST_SECRET").unwrap(), "abc123");
+        assert!(matches!(resolve("env:CLOUDFLAREDDNS_TEST_EMPTY"), Err(SecretError::Empty)));
+        assert!(matches!(resolve("env:CLOUDFLAREDDNS_TEST_UNSET"), Err(SecretError::MissingVariable(v)) if v == "CLOUDFLAREDDNS_TEST_UNSET"));
+    }
+
+    #[test]
+    fn reads_trimmed_files() {
+        let dir = dir("file");
+        fs::write(dir.join("key"), "  abc123\n").unwrap();
+        fs::write(dir.join("empty"), "\n").unwrap();
+
+        assert_eq!(resolve(&format!("file:{}", dir.join("key").display())).unwrap(), "abc123");
+        assert!(matches!(resolve(&format!("file:{}", dir.join("empty").display())), Err(SecretError::Empty)));
+        assert!(matches!(resolve(&format!("file:{}", dir.join("missing").display())), Err(SecretError::FileError(_))));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reads_credentials() {
+        let dir = dir("credential");
+        fs::write(dir.join("key"), "abc123\n").unwrap();
+
+        // Both cases share $CREDENTIALS_DIRECTORY, so they run in the same test
+        env::remove_var("CREDENTIALS_DIRECTORY");
+        assert!(matches!(resolve("credential:key"), Err(SecretError::NoCredentialsDirectory)));
+
+        env::set_var("CREDENTIALS_DIRECTORY", &dir);
+        assert_eq!(resolve("credential:key").unwrap(), "abc123");
+        assert!(matches!(resolve("credential:missing"), Err(SecretError::FileError(_))));
+
+        env::remove_var("CREDENTIALS_DIRECTORY");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
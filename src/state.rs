@@ -0,0 +1,182 @@
+use std::{io::ErrorKind, path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{fs, io::AsyncWriteExt, sync::Mutex};
+
+use crate::{data::{Entry, RecordType}, error::StateError};
+
+/// What was last published for an entry, identified by its zone, name and record type
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct EntryState {
+    pub zone_id : String,
+    pub name : String,
+    #[serde(rename = "type")]
+    pub record_type : RecordType,
+    /// The ID of the updated record, unset for record sets
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id : Option<String>,
+    /// The published content, such as the address of an A record
+    pub content : String,
+    /// The published structured data of HTTPS, SVCB, URI and SRV records, whose content Cloudflare formats on its own
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data : Option<Value>,
+    /// The content computed from the detected addresses when a change made outside of the updater was adopted
+    /// in its place, which is published once the addresses change
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub desired : Option<String>,
+    /// When the content was published, in seconds since the Unix epoch
+    pub published_at : u64
+}
+
+impl EntryState {
+    fn is_for(&self, zone_id : &str, entry : &Entry) -> bool {
+        self.zone_id == zone_id && self.name == entry.name && self.record_type == entry.record_type
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct StateContents {
+    #[serde(default)]
+    entries : Vec<EntryState>
+}
+
+/// The last published state of every entry, kept on disk so that restarts do not republish records
+/// that are already up to date.
+pub struct StateFile {
+    path : PathBuf,
+    entries : Mutex<Vec<EntryState>>
+}
+
+impl StateFile {
+    /// Creates an empty state which is saved to `path`.
+    pub fn new(path : impl Into<PathBuf>) -> Self {
+        Self { path: path.into(), entries: Mutex::new(Vec::new()) }
+    }
+
+    /// Reads the state saved at `path`, which is empty if the file does not exist yet.
+    pub async fn load(path : impl Into<PathBuf>) -> Result<Self, StateError> {
+        let path = path.into();
+
+        let contents = match fs::read_to_string(&path).await {
+            Ok(v) => serde_json::from_str(&v)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => StateContents::default(),
+            Err(e) => return Err(e.into())
+        };
+
+        Ok(Self { path, entries: Mutex::new(contents.entries) })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub async fn get(&self, zone_id : &str, entry : &Entry) -> Option<EntryState> {
+        self.entries.lock().await.iter().find(|v| v.is_for(zone_id, entry)).cloned()
+    }
+
+    /// Records that `content` and `data` were published for `entry`, or adopted in place of `desired`, and saves
+    /// the state.
+    pub async fn set(&self, zone_id : &str, entry : &Entry, id : Option<&str>, content : &str, data : Option<&Value>, desired : Option<&str>) -> Result<(), StateError> {
+        let published_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|v| v.as_secs()).unwrap_or(0);
+
+        let state = EntryState {
+            zone_id: zone_id.to_owned(),
+            name: entry.name.clone(),
+            record_type: entry.record_type,
+            id: id.map(str::to_owned),
+            content: content.to_owned(),
+            data: data.cloned(),
+            desired: desired.map(str::to_owned),
+            published_at
+        };
+
+        // The lock is held while saving so that concurrent updates are written in order
+        let mut entries = self.entries.lock().await;
+
+        match entries.iter_mut().find(|v| v.is_for(zone_id, entry)) {
+            Some(v) => *v = state,
+            None => entries.push(state)
+        }
+
+        let contents = serde_json::to_string_pretty(&StateContents { entries: entries.clone() })?;
+
+        self.save(&contents).await
+    }
+
+    /// Writes `contents` to a temporary file which then replaces the state file, so that the state
+    /// file is never left half written.
+    async fn save(&self, contents : &str) -> Result<(), StateError> {
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+
+        let mut file = fs::File::create(&tmp).await?;
+        file.write_all(contents.as_bytes()).await?;
+        file.sync_all().await?;
+
+        fs::rename(&tmp, &self.path).await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// A directory only used by the test called `name`
+    fn dir(name : &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cloudflareddns-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn entry(name : &str, record_type : &str) -> Entry {
+        toml::from_str(&format!("name = \"{}\"\ntype = \"{}\"", name, record_type)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn missing_file_loads_as_empty() {
+        let dir = dir("missing");
+        let path = dir.join("state.json");
+
+        let state = StateFile::load(&path).await.unwrap();
+
+        assert_eq!(state.path(), path);
+        assert!(state.get("z", &entry("a.example.com", "TXT")).await.is_none());
+        assert!(!path.exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn saved_state_round_trips() {
+        let dir = dir("round-trip");
+        let path = dir.join("state.json");
+        let txt = entry("a.example.com", "TXT");
+        let srv = entry("_sip._tcp.example.com", "SRV");
+
+        let state = StateFile::new(&path);
+        state.set("z", &txt, Some("r1"), "old", None, None).await.unwrap();
+        state.set("z", &txt, Some("r1"), "new", None, Some("desired")).await.unwrap();
+        state.set("z", &srv, None, "0 5060 sip.example.com", Some(&json!({ "port": 5060 })), None).await.unwrap();
+
+        let loaded = StateFile::load(&path).await.unwrap();
+        let known = loaded.get("z", &txt).await.unwrap();
+
+        assert_eq!(known.id.as_deref(), Some("r1"));
+        assert_eq!(known.content, "new");
+        assert_eq!(known.desired.as_deref(), Some("desired"));
+        assert_eq!(loaded.get("z", &srv).await.unwrap().data, Some(json!({ "port": 5060 })));
+        assert!(loaded.get("other", &txt).await.is_none());
+        assert!(loaded.get("z", &entry("a.example.com", "CNAME")).await.is_none());
+        assert!(!dir.join("state.json.tmp").exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
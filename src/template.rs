@@ -0,0 +1,95 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use crate::error::TemplateError;
+
+/// Renders a record content template, replacing `{ipv4}` and `{ipv6}` with the detected addresses.
+/// `{{` and `}}` stand for literal braces. Returns `None` when an address used by the template was
+/// not detected.
+pub fn render(template : &str, ipv4 : Option<Ipv4Addr>, ipv6 : Option<Ipv6Addr>) -> Result<Option<String>, TemplateError> {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(i) = rest.find(['{', '}']) {
+        out.push_str(&rest[..i]);
+
+        if rest[i..].starts_with("{{") {
+            out.push('{');
+            rest = &rest[i + 2..];
+            continue;
+        }
+        if rest[i..].starts_with("}}") {
+            out.push('}');
+            rest = &rest[i + 2..];
+            continue;
+        }
+        if rest[i..].starts_with('}') {
+            return Err(TemplateError::Unmatched);
+        }
+
+        let end = rest[i..].find('}').ok_or(TemplateError::Unmatched)? + i;
+
+        match &rest[i + 1..end] {
+            "ipv4" => match ipv4 {
+                Some(v) => out.push_str(&v.to_string()),
+                None => return Ok(None)
+            },
+            "ipv6" => match ipv6 {
+                Some(v) => out.push_str(&v.to_string()),
+                None => return Ok(None)
+            },
+            name => return Err(TemplateError::UnknownPlaceholder(name.to_owned()))
+        }
+
+        rest = &rest[end + 1..];
+    }
+
+    out.push_str(rest);
+
+    Ok(Some(out))
+}
+
+/// Checks that `template` only uses known placeholders and balanced braces.
+pub fn validate(template : &str) -> Result<(), TemplateError> {
+    render(template, Some(Ipv4Addr::UNSPECIFIED), Some(Ipv6Addr::UNSPECIFIED)).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IPV4 : Option<Ipv4Addr> = Some(Ipv4Addr::new(203, 0, 113, 7));
+    const IPV6 : Option<Ipv6Addr> = Some(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 7));
+
+    #[test]
+    fn replaces_placeholders() {
+        assert_eq!(render("v=spf1 ip4:{ipv4} ip6:{ipv6} -all", IPV4, IPV6).unwrap().as_deref(), Some("v=spf1 ip4:203.0.113.7 ip6:2001:db8::7 -all"));
+        assert_eq!(render("no placeholders", None, None).unwrap().as_deref(), Some("no placeholders"));
+    }
+
+    #[test]
+    fn escapes_braces() {
+        assert_eq!(render("{{ipv4}} is {ipv4}, {{}}", IPV4, None).unwrap().as_deref(), Some("{ipv4} is 203.0.113.7, {}"));
+        assert_eq!(render("}}{{{ipv4}}}", IPV4, None).unwrap().as_deref(), Some("}{203.0.113.7}"));
+    }
+
+    #[test]
+    fn missing_address_renders_nothing() {
+        assert_eq!(render("{ipv4} {ipv6}", IPV4, None).unwrap(), None);
+        assert_eq!(render("{ipv6}", None, IPV6).unwrap().as_deref(), Some("2001:db8::7"));
+    }
+
+    #[test]
+    fn rejects_unknown_placeholders() {
+        assert!(matches!(render("{ipv5}", IPV4, IPV6), Err(TemplateError::UnknownPlaceholder(v)) if v == "ipv5"));
+        assert!(matches!(validate("{}"), Err(TemplateError::UnknownPlaceholder(v)) if v.is_empty()));
+    }
+
+    #[test]
+    fn rejects_unmatched_braces() {
+        assert!(matches!(validate("a } b"), Err(TemplateError::Unmatched)));
+        assert!(matches!(validate("{ipv4"), Err(TemplateError::Unmatched)));
+        assert!(matches!(validate("{ipv4} {"), Err(TemplateError::Unmatched)));
+        assert!(matches!(validate("{{ipv4}"), Err(TemplateError::Unmatched)));
+        assert!(validate("{{ipv4}}").is_ok());
+    }
+}